@@ -0,0 +1,721 @@
+//! Generate the instruction decoder, the per instruction handlers and the GraphQL schema from
+//! `instruction.json`, so the three stay in sync when the instruction layout changes.
+//!
+//! Everything is written to `OUT_DIR`, the build never touches the source tree. The decoder
+//! and handler code is included by `src/generated`. The schema holds the entity types of the
+//! instructions followed by the hand written entities of `src/entities.graphql`; a test keeps
+//! the checked in `src/schema.graphql` and `releases/schema.graphql` in sync with it.
+use serde_json::Value;
+use std::env;
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+
+const INSTRUCTION_JSON: &str = "instruction.json";
+const ENTITIES_GRAPHQL: &str = "src/entities.graphql";
+
+/// Columns every instruction entity starts with, written by `InstructionContext::insert_header`.
+const HEADER_FIELDS: &[(&str, &str)] = &[
+    ("program_id", "String"),
+    ("dex_version", "String"),
+    ("slot", "BigInt"),
+    ("block_hash", "String"),
+    ("timestamp", "BigInt"),
+    ("signature", "String"),
+    ("fee_payer", "String"),
+    ("success", "Boolean"),
+    ("error", "String"),
+    ("tx_index", "BigInt"),
+    ("instruction_index", "BigInt"),
+    ("inner_index", "BigInt"),
+    ("invoking_program", "String"),
+    ("instruction_version", "BigInt"),
+];
+
+struct Account {
+    index: u64,
+    name: String,
+    entity: Option<String>,
+}
+
+struct Field {
+    name: String,
+    data_type: String,
+    length: usize,
+    format: Option<String>,
+}
+
+enum Definition {
+    Struct(Vec<Field>),
+    Enum(Vec<(String, u64)>),
+}
+
+struct Variant {
+    name: String,
+    inner_type: Option<String>,
+    tag: u64,
+    accounts: Vec<Account>,
+    /// Fields holding the limit price and quantity in lots, converted to UI `price`/`size`.
+    ui_amounts: Option<(String, String)>,
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed={}", INSTRUCTION_JSON);
+    println!("cargo:rerun-if-changed={}", ENTITIES_GRAPHQL);
+    println!("cargo:rerun-if-changed=build.rs");
+
+    let json: Value = serde_json::from_str(
+        &fs::read_to_string(INSTRUCTION_JSON).expect("Cannot read instruction.json"),
+    )
+    .expect("Invalid instruction.json");
+    let name = json["name"].as_str().expect("Missing instruction name");
+    let variants = parse_variants(&json["variants"]);
+    let definitions = parse_definitions(&json["definitions"]);
+
+    let out_dir = env::var("OUT_DIR").expect("OUT_DIR is not set");
+    write_if_changed(
+        &Path::new(&out_dir).join("instruction.rs"),
+        &gen_instruction(name, &variants, &definitions),
+    );
+    write_if_changed(
+        &Path::new(&out_dir).join("handler.rs"),
+        &gen_handler(name, &variants, &definitions),
+    );
+    let entities = fs::read_to_string(ENTITIES_GRAPHQL).expect("Cannot read entities.graphql");
+    let schema = format!("{}{}", gen_schema(&variants, &definitions), entities);
+    write_if_changed(&Path::new(&out_dir).join("schema.graphql"), &schema);
+}
+
+/// Only touch outputs whose content changed, so that unchanged code is not rebuilt.
+fn write_if_changed(path: &Path, content: &str) {
+    if fs::read_to_string(path).ok().as_deref() != Some(content) {
+        fs::write(path, content).unwrap_or_else(|err| panic!("Cannot write {:?}: {}", path, err));
+    }
+}
+
+fn parse_variants(value: &Value) -> Vec<Variant> {
+    value
+        .as_array()
+        .expect("variants must be an array")
+        .iter()
+        .map(|variant| Variant {
+            name: variant["name"]
+                .as_str()
+                .expect("Missing variant name")
+                .to_string(),
+            inner_type: variant["innerType"].as_str().map(String::from),
+            tag: variant["variantTag"].as_u64().expect("Missing variantTag"),
+            accounts: variant["accounts"]
+                .as_array()
+                .map(|accounts| {
+                    accounts
+                        .iter()
+                        .map(|account| Account {
+                            index: account["index"].as_u64().expect("Missing account index"),
+                            name: account["name"]
+                                .as_str()
+                                .expect("Missing account name")
+                                .to_string(),
+                            entity: account["entity"].as_str().map(String::from),
+                        })
+                        .collect()
+                })
+                .unwrap_or_default(),
+            ui_amounts: variant["uiAmounts"].as_object().map(|amounts| {
+                (
+                    amounts["price"]
+                        .as_str()
+                        .expect("Missing uiAmounts.price")
+                        .to_string(),
+                    amounts["size"]
+                        .as_str()
+                        .expect("Missing uiAmounts.size")
+                        .to_string(),
+                )
+            }),
+        })
+        .collect()
+}
+
+fn parse_definitions(value: &Value) -> Vec<(String, Definition)> {
+    value
+        .as_object()
+        .expect("definitions must be an object")
+        .iter()
+        .map(|(name, definition)| {
+            let definition = match definition["type"].as_str() {
+                Some("struct") => Definition::Struct(
+                    definition["properties"]
+                        .as_array()
+                        .expect("Missing struct properties")
+                        .iter()
+                        .map(|property| {
+                            let data_type =
+                                property["dataType"].as_str().expect("Missing dataType");
+                            Field {
+                                name: property["name"]
+                                    .as_str()
+                                    .expect("Missing property name")
+                                    .to_string(),
+                                data_type: data_type.to_string(),
+                                length: property["length"]
+                                    .as_u64()
+                                    .map(|length| length as usize)
+                                    .unwrap_or_else(|| default_length(data_type)),
+                                format: property["format"].as_str().map(String::from),
+                            }
+                        })
+                        .collect(),
+                ),
+                Some("enum") => Definition::Enum(
+                    definition["variants"]
+                        .as_array()
+                        .expect("Missing enum variants")
+                        .iter()
+                        .map(|variant| {
+                            (
+                                variant["name"]
+                                    .as_str()
+                                    .expect("Missing enum variant name")
+                                    .to_string(),
+                                variant["variantTag"]
+                                    .as_u64()
+                                    .expect("Missing enum variantTag"),
+                            )
+                        })
+                        .collect(),
+                ),
+                other => panic!("Unsupported definition type {:?} of {}", other, name),
+            };
+            (name.clone(), definition)
+        })
+        .collect()
+}
+
+/// Enums are encoded as a `u32` tag.
+fn default_length(data_type: &str) -> usize {
+    match data_type {
+        "u8" => 1,
+        "u16" => 2,
+        "u32" => 4,
+        "u64" | "NonZeroU64" => 8,
+        "u128" => 16,
+        "Pubkey" => 32,
+        _ => 4,
+    }
+}
+
+fn is_primitive(data_type: &str) -> bool {
+    matches!(data_type, "u8" | "u16" | "u32" | "u64" | "u128")
+}
+
+fn snake_case(name: &str) -> String {
+    let mut snake = String::new();
+    let mut prev_lower = false;
+    for ch in name.chars() {
+        if ch.is_uppercase() {
+            if prev_lower {
+                snake.push('_');
+            }
+            snake.extend(ch.to_lowercase());
+            prev_lower = false;
+        } else {
+            snake.push(ch);
+            prev_lower = ch.is_lowercase() || ch.is_ascii_digit();
+        }
+    }
+    snake
+}
+
+fn find_struct<'a>(definitions: &'a [(String, Definition)], name: &str) -> Option<&'a [Field]> {
+    definitions
+        .iter()
+        .find_map(|(def_name, definition)| match definition {
+            Definition::Struct(fields) if def_name == name => Some(fields.as_slice()),
+            _ => None,
+        })
+}
+
+fn struct_len(fields: &[Field]) -> usize {
+    fields.iter().map(|field| field.length).sum()
+}
+
+fn gen_instruction(
+    name: &str,
+    variants: &[Variant],
+    definitions: &[(String, Definition)],
+) -> String {
+    let mut code = String::new();
+    for (def_name, definition) in definitions {
+        match definition {
+            Definition::Struct(fields) => {
+                writeln!(
+                    code,
+                    "#[derive(Clone, PartialEq, Debug, Deserialize, Serialize)]"
+                )
+                .unwrap();
+                writeln!(code, "pub struct {} {{", def_name).unwrap();
+                for field in fields {
+                    writeln!(code, "    pub {}: {},", field.name, field.data_type).unwrap();
+                }
+                writeln!(code, "}}").unwrap();
+                writeln!(code, "impl {} {{", def_name).unwrap();
+                writeln!(
+                    code,
+                    "    pub fn unpack(input: &[u8; {}]) -> Result<Self, DecodeError> {{",
+                    struct_len(fields)
+                )
+                .unwrap();
+                let names: Vec<&str> = fields.iter().map(|field| field.name.as_str()).collect();
+                let lengths: Vec<String> = fields
+                    .iter()
+                    .map(|field| field.length.to_string())
+                    .collect();
+                writeln!(
+                    code,
+                    "        let ({},) = array_refs![input, {}];",
+                    names.join(", "),
+                    lengths.join(", ")
+                )
+                .unwrap();
+                writeln!(code, "        Ok({} {{", def_name).unwrap();
+                for field in fields {
+                    let decode = match field.data_type.as_str() {
+                        "NonZeroU64" => format!("non_zero_u64({:?}, {})?", field.name, field.name),
+                        "Pubkey" => format!("Pubkey::new_from_array(*{})", field.name),
+                        data_type if is_primitive(data_type) => {
+                            format!("{}::from_le_bytes(*{})", data_type, field.name)
+                        }
+                        data_type => format!("{}::unpack({})?", data_type, field.name),
+                    };
+                    writeln!(code, "            {}: {},", field.name, decode).unwrap();
+                }
+                writeln!(code, "        }})").unwrap();
+                writeln!(code, "    }}").unwrap();
+                writeln!(code, "}}").unwrap();
+            }
+            Definition::Enum(enum_variants) => {
+                writeln!(
+                    code,
+                    "#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]"
+                )
+                .unwrap();
+                writeln!(code, "pub enum {} {{", def_name).unwrap();
+                for (variant, _) in enum_variants {
+                    writeln!(code, "    {},", variant).unwrap();
+                }
+                writeln!(code, "}}").unwrap();
+                writeln!(code, "impl {} {{", def_name).unwrap();
+                writeln!(
+                    code,
+                    "    pub fn unpack(input: &[u8; 4]) -> Result<Self, DecodeError> {{"
+                )
+                .unwrap();
+                writeln!(code, "        let tag_val = u32::from_le_bytes(*input);").unwrap();
+                writeln!(code, "        match tag_val {{").unwrap();
+                for (variant, tag) in enum_variants {
+                    writeln!(
+                        code,
+                        "            {} => Ok({}::{}),",
+                        tag, def_name, variant
+                    )
+                    .unwrap();
+                }
+                writeln!(
+                    code,
+                    "            _ => Err(DecodeError::BadEnumTag {{ name: {:?}, tag: tag_val }}),",
+                    def_name
+                )
+                .unwrap();
+                writeln!(code, "        }}").unwrap();
+                writeln!(code, "    }}").unwrap();
+                writeln!(code, "}}").unwrap();
+            }
+        }
+    }
+
+    writeln!(
+        code,
+        "#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]"
+    )
+    .unwrap();
+    writeln!(code, "pub enum {} {{", name).unwrap();
+    for variant in variants {
+        match &variant.inner_type {
+            Some(inner_type) => writeln!(code, "    {}({}),", variant.name, inner_type).unwrap(),
+            None => writeln!(code, "    {},", variant.name).unwrap(),
+        }
+    }
+    writeln!(code, "}}").unwrap();
+    writeln!(code, "impl {} {{", name).unwrap();
+    writeln!(
+        code,
+        "    fn unpack_data(data: &[u8], tag_val: u32) -> Result<Self, DecodeError> {{"
+    )
+    .unwrap();
+    writeln!(code, "        match tag_val {{").unwrap();
+    for variant in variants {
+        match &variant.inner_type {
+            None => writeln!(
+                code,
+                "            {} => Ok({}::{}),",
+                variant.tag, name, variant.name
+            )
+            .unwrap(),
+            Some(inner_type) => {
+                let (len, decode) = if is_primitive(inner_type) {
+                    (
+                        default_length(inner_type),
+                        format!("{}::from_le_bytes(*field_slice)", inner_type),
+                    )
+                } else {
+                    let fields = find_struct(definitions, inner_type)
+                        .unwrap_or_else(|| panic!("Missing definition of {}", inner_type));
+                    (
+                        struct_len(fields),
+                        format!("{}::unpack(field_slice)?", inner_type),
+                    )
+                };
+                writeln!(code, "            {} => {{", variant.tag).unwrap();
+                writeln!(
+                    code,
+                    "                let field_slice = array_ref![take(data, {})?, 0, {}];",
+                    len, len
+                )
+                .unwrap();
+                writeln!(
+                    code,
+                    "                Ok({}::{}({}))",
+                    name, variant.name, decode
+                )
+                .unwrap();
+                writeln!(code, "            }}").unwrap();
+            }
+        }
+    }
+    writeln!(
+        code,
+        "            _ => Err(DecodeError::UnknownTag(tag_val)),"
+    )
+    .unwrap();
+    writeln!(code, "        }}").unwrap();
+    writeln!(code, "    }}").unwrap();
+    writeln!(code, "    pub fn name(&self) -> &'static str {{").unwrap();
+    writeln!(code, "        match self {{").unwrap();
+    for variant in variants {
+        writeln!(
+            code,
+            "            {} => {:?},",
+            variant_pattern(name, variant),
+            variant.name
+        )
+        .unwrap();
+    }
+    writeln!(code, "        }}").unwrap();
+    writeln!(code, "    }}").unwrap();
+    gen_account_index(
+        &mut code,
+        name,
+        variants,
+        "market_account",
+        "Index of the market account, for the instructions that take one.",
+        market_account,
+    );
+    gen_account_index(
+        &mut code,
+        name,
+        variants,
+        "open_orders_account",
+        "Index of the open orders account, for the instructions that take one.",
+        open_orders_account,
+    );
+    writeln!(code, "}}").unwrap();
+    code
+}
+
+/// Method of the instruction enum returning the account index picked by `index` per variant.
+fn gen_account_index(
+    code: &mut String,
+    name: &str,
+    variants: &[Variant],
+    function: &str,
+    doc: &str,
+    index: fn(&Variant) -> Option<u64>,
+) {
+    writeln!(code, "    /// {}", doc).unwrap();
+    writeln!(code, "    pub fn {}(&self) -> Option<usize> {{", function).unwrap();
+    writeln!(code, "        match self {{").unwrap();
+    for variant in variants {
+        let index = match index(variant) {
+            Some(index) => format!("Some({})", index),
+            None => "None".to_string(),
+        };
+        writeln!(
+            code,
+            "            {} => {},",
+            variant_pattern(name, variant),
+            index
+        )
+        .unwrap();
+    }
+    writeln!(code, "        }}").unwrap();
+    writeln!(code, "    }}").unwrap();
+}
+
+/// Pattern matching `variant` whatever its argument.
+fn variant_pattern(name: &str, variant: &Variant) -> String {
+    match variant.inner_type {
+        Some(_) => format!("{}::{}(_)", name, variant.name),
+        None => format!("{}::{}", name, variant.name),
+    }
+}
+
+/// Index of the account of `variant` referencing the `Market` entity.
+fn market_account(variant: &Variant) -> Option<u64> {
+    variant
+        .accounts
+        .iter()
+        .find(|account| account.entity.as_deref() == Some("Market"))
+        .map(|account| account.index)
+}
+
+/// Index of the `open_orders` account of `variant`.
+fn open_orders_account(variant: &Variant) -> Option<u64> {
+    variant
+        .accounts
+        .iter()
+        .find(|account| account.name == "open_orders")
+        .map(|account| account.index)
+}
+
+/// Expression for the account at `index` of `accounts`.
+fn account_expr(index: u64) -> String {
+    match index {
+        0 => "accounts.first()".to_string(),
+        index => format!("accounts.get({})", index),
+    }
+}
+
+/// Rust expression turning field `arg.<name>` into an entity `Value`.
+fn field_value(field: &Field) -> String {
+    match field.data_type.as_str() {
+        "NonZeroU64" => format!("Value::from(arg.{}.get())", field.name),
+        "Pubkey" => format!("Value::from(arg.{}.to_string())", field.name),
+        data_type if is_primitive(data_type) => format!("Value::from(arg.{})", field.name),
+        _ => format!(
+            "Value::from(serde_json::to_string(&arg.{}).unwrap_or_default())",
+            field.name
+        ),
+    }
+}
+
+fn gen_handler(name: &str, variants: &[Variant], definitions: &[(String, Definition)]) -> String {
+    let mut code = String::new();
+    writeln!(code, "impl Handler {{").unwrap();
+    writeln!(code, "    fn dispatch(").unwrap();
+    writeln!(code, "        &self,").unwrap();
+    writeln!(code, "        block: &SolanaBlock,").unwrap();
+    writeln!(code, "        transaction: &TransactionWithStatusMeta,").unwrap();
+    writeln!(code, "        ctx: &InstructionContext,").unwrap();
+    writeln!(code, "        program_id: &Pubkey,").unwrap();
+    writeln!(code, "        accounts: &[Pubkey],").unwrap();
+    writeln!(code, "        instruction: {},", name).unwrap();
+    writeln!(code, "    ) -> Result<(), anyhow::Error> {{").unwrap();
+    writeln!(code, "        match instruction {{").unwrap();
+    for variant in variants {
+        let function = format!("process_{}", snake_case(&variant.name));
+        match variant.inner_type {
+            Some(_) => {
+                writeln!(code, "            {}::{}(arg) => {{", name, variant.name).unwrap();
+                writeln!(
+                    code,
+                    "                self.{}(block, transaction, ctx, program_id, accounts, arg)",
+                    function
+                )
+                .unwrap();
+            }
+            None => {
+                writeln!(code, "            {}::{} => {{", name, variant.name).unwrap();
+                writeln!(
+                    code,
+                    "                self.{}(block, transaction, ctx, program_id, accounts)",
+                    function
+                )
+                .unwrap();
+            }
+        }
+        writeln!(code, "            }}").unwrap();
+    }
+    writeln!(code, "        }}").unwrap();
+    writeln!(code, "    }}").unwrap();
+
+    for variant in variants {
+        let function = format!("process_{}", snake_case(&variant.name));
+        // Handlers get the whole instruction, most only write the decoded fields
+        let uses_accounts = !variant.accounts.is_empty()
+            || market_account(variant).is_some()
+            || variant.ui_amounts.is_some();
+        writeln!(code, "    pub fn {}(", function).unwrap();
+        writeln!(code, "        &self,").unwrap();
+        writeln!(code, "        _block: &SolanaBlock,").unwrap();
+        writeln!(code, "        _transaction: &TransactionWithStatusMeta,").unwrap();
+        writeln!(code, "        ctx: &InstructionContext,").unwrap();
+        writeln!(code, "        _program_id: &Pubkey,").unwrap();
+        if uses_accounts {
+            writeln!(code, "        accounts: &[Pubkey],").unwrap();
+        } else {
+            writeln!(code, "        _accounts: &[Pubkey],").unwrap();
+        }
+        if let Some(inner_type) = &variant.inner_type {
+            writeln!(code, "        arg: {},", inner_type).unwrap();
+        }
+        writeln!(code, "    ) -> Result<(), anyhow::Error> {{").unwrap();
+        let market = match market_account(variant) {
+            Some(index) => account_expr(index),
+            None => "None".to_string(),
+        };
+        match variant.inner_type {
+            Some(_) => writeln!(
+                code,
+                "        log::trace!(\"{{}} arg={{:?}}\", ctx.log_fields({:?}, {}), &arg);",
+                variant.name, market
+            )
+            .unwrap(),
+            None => writeln!(
+                code,
+                "        log::trace!(\"{{}}\", ctx.log_fields({:?}, {}));",
+                variant.name, market
+            )
+            .unwrap(),
+        }
+        writeln!(
+            code,
+            "        let mut map: HashMap<Attribute, Value> = HashMap::default();"
+        )
+        .unwrap();
+        writeln!(
+            code,
+            "        map.insert(\"id\".to_string(), Value::from(ctx.entity_id()));"
+        )
+        .unwrap();
+        writeln!(code, "        ctx.insert_header(&mut map);").unwrap();
+        for account in &variant.accounts {
+            writeln!(
+                code,
+                "        map.insert({:?}.to_string(), Value::from({}.map(|pubkey| pubkey.to_string()).unwrap_or_default()));",
+                account.name,
+                account_expr(account.index)
+            )
+            .unwrap();
+        }
+        match variant.inner_type.as_deref() {
+            None => {}
+            Some(inner_type) if is_primitive(inner_type) => {
+                writeln!(
+                    code,
+                    "        map.insert(\"value\".to_string(), Value::from(arg));"
+                )
+                .unwrap();
+            }
+            Some(inner_type) => {
+                let fields = find_struct(definitions, inner_type)
+                    .unwrap_or_else(|| panic!("Missing definition of {}", inner_type));
+                for field in fields {
+                    match field.format.as_deref() {
+                        Some("orderId") => writeln!(
+                            code,
+                            "        order_id::insert_order_id(&mut map, arg.{}, &arg.side);",
+                            field.name
+                        )
+                        .unwrap(),
+                        _ => {
+                            let value = field_value(field);
+                            writeln!(
+                                code,
+                                "        map.insert({:?}.to_string(), {});",
+                                field.name, value
+                            )
+                            .unwrap()
+                        }
+                    }
+                    if let Some((price, size)) = &variant.ui_amounts {
+                        if *size == field.name {
+                            writeln!(
+                                code,
+                                "        derived::market::insert_price_size(&mut map, accounts.first(), arg.{}.get(), Some(arg.{}.get()));",
+                                price, size
+                            )
+                            .unwrap();
+                        }
+                    }
+                }
+            }
+        }
+        writeln!(code, "        Entity::from(map).save({:?});", variant.name).unwrap();
+        writeln!(code, "        Ok(())").unwrap();
+        writeln!(code, "    }}").unwrap();
+    }
+    writeln!(code, "}}").unwrap();
+    code
+}
+
+/// GraphQL columns of field `field`, in the order the handler writes them.
+fn field_columns(field: &Field) -> Vec<(String, &'static str)> {
+    match (field.format.as_deref(), field.data_type.as_str()) {
+        (Some("orderId"), _) => vec![
+            (field.name.clone(), "BigInt"),
+            ("price_lots".to_string(), "BigInt"),
+            ("seq_num".to_string(), "BigInt"),
+        ],
+        (_, "Pubkey") => vec![(field.name.clone(), "String")],
+        (_, data_type) if data_type == "NonZeroU64" || is_primitive(data_type) => {
+            vec![(field.name.clone(), "BigInt")]
+        }
+        _ => vec![(field.name.clone(), "String")],
+    }
+}
+
+fn gen_schema(variants: &[Variant], definitions: &[(String, Definition)]) -> String {
+    let mut schema = String::new();
+    for variant in variants {
+        let mut columns: Vec<(String, String)> = HEADER_FIELDS
+            .iter()
+            .map(|(name, data_type)| (name.to_string(), data_type.to_string()))
+            .collect();
+        for account in &variant.accounts {
+            columns.push((
+                account.name.clone(),
+                account
+                    .entity
+                    .clone()
+                    .unwrap_or_else(|| "String".to_string()),
+            ));
+        }
+        match variant.inner_type.as_deref() {
+            None => {}
+            Some(inner_type) if is_primitive(inner_type) => {
+                columns.push(("value".to_string(), "BigInt".to_string()));
+            }
+            Some(inner_type) => {
+                for field in find_struct(definitions, inner_type).unwrap_or_default() {
+                    for (name, data_type) in field_columns(field) {
+                        columns.push((name, data_type.to_string()));
+                    }
+                    if let Some((_, size)) = &variant.ui_amounts {
+                        if *size == field.name {
+                            columns.push(("price".to_string(), "BigDecimal".to_string()));
+                            columns.push(("size".to_string(), "BigDecimal".to_string()));
+                        }
+                    }
+                }
+            }
+        }
+        writeln!(schema, "type {} @entity {{", variant.name).unwrap();
+        write!(schema, "    id: ID!").unwrap();
+        for (name, data_type) in columns {
+            write!(schema, ",\n\t{}: {}", name, data_type).unwrap();
+        }
+        writeln!(schema, "\n}}").unwrap();
+    }
+    schema
+}
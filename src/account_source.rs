@@ -0,0 +1,347 @@
+use crate::state::event_queue::EventQueue;
+use crate::state::market::MarketState;
+use crate::state::open_orders::OpenOrders;
+use crate::state::request_queue::RequestQueue;
+use crate::state::slab::Slab;
+use crate::state::StateError;
+use solana_client::rpc_client::RpcClient;
+use solana_program::pubkey::Pubkey;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+
+/// Accounts per `getMultipleAccounts` request, the limit of the RPC nodes.
+pub const MAX_MULTIPLE_ACCOUNTS: usize = 100;
+
+/// Where derived state reads account data from.
+pub trait AccountSource: Send + Sync {
+    /// Data of `pubkey` at the end of `slot`, `None` when it is not available.
+    fn account_data(&self, pubkey: &Pubkey, slot: u64) -> Option<Vec<u8>>;
+    /// Data of several accounts at the end of `slot`, in the order of `pubkeys`.
+    fn multiple_account_data(&self, pubkeys: &[Pubkey], slot: u64) -> Vec<Option<Vec<u8>>> {
+        pubkeys
+            .iter()
+            .map(|pubkey| self.account_data(pubkey, slot))
+            .collect()
+    }
+    /// Account data before and after `slot`.
+    fn account_data_around(&self, pubkey: &Pubkey, slot: u64) -> Option<(Vec<u8>, Vec<u8>)> {
+        let before = self.account_data(pubkey, slot.checked_sub(1)?)?;
+        let after = self.account_data(pubkey, slot)?;
+        Some((before, after))
+    }
+}
+
+/// `SERUM_SNAPSHOT_DIR` when set, otherwise the RPC node at `SOLANA_RPC_URL`. Without either,
+/// nothing is derived from account data.
+pub fn load_account_source() -> Option<Arc<dyn AccountSource>> {
+    if let Ok(root) = env::var("SERUM_SNAPSHOT_DIR") {
+        return Some(Arc::new(SnapshotDir::new(root)));
+    }
+    let url = env::var("SOLANA_RPC_URL").ok()?;
+    log::warn!(
+        "Read accounts from {} without SERUM_SNAPSHOT_DIR: fills are not derived and balances are \
+         only exact at the tip of the chain",
+        url
+    );
+    Some(Arc::new(RpcSource::new(url)))
+}
+
+/// Account data captured outside of the indexer, laid out as `<root>/<pubkey>/<slot>` where
+/// each file holds the raw account data at the end of that slot.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SnapshotDir {
+    root: PathBuf,
+}
+
+impl SnapshotDir {
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        SnapshotDir { root: root.into() }
+    }
+}
+
+impl AccountSource for SnapshotDir {
+    /// Latest captured data of `pubkey` at or before `slot`.
+    fn account_data(&self, pubkey: &Pubkey, slot: u64) -> Option<Vec<u8>> {
+        let latest_slot = fs::read_dir(self.root.join(pubkey.to_string()))
+            .ok()?
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| entry.file_name().to_str()?.parse::<u64>().ok())
+            .filter(|snapshot_slot| *snapshot_slot <= slot)
+            .max()?;
+        fs::read(
+            self.root
+                .join(pubkey.to_string())
+                .join(latest_slot.to_string()),
+        )
+        .ok()
+    }
+}
+
+/// Accounts read from an RPC node. Nodes only serve the latest state, so the data is exact
+/// only while indexing the tip of the chain and derivations comparing two slots get nothing.
+pub struct RpcSource {
+    client: RpcClient,
+}
+
+impl RpcSource {
+    pub fn new(url: String) -> Self {
+        RpcSource {
+            client: RpcClient::new(url),
+        }
+    }
+}
+
+impl AccountSource for RpcSource {
+    fn account_data(&self, pubkey: &Pubkey, slot: u64) -> Option<Vec<u8>> {
+        self.multiple_account_data(&[*pubkey], slot).pop().flatten()
+    }
+    fn multiple_account_data(&self, pubkeys: &[Pubkey], _slot: u64) -> Vec<Option<Vec<u8>>> {
+        pubkeys
+            .chunks(MAX_MULTIPLE_ACCOUNTS)
+            .flat_map(|chunk| match self.client.get_multiple_accounts(chunk) {
+                Ok(accounts) => accounts
+                    .into_iter()
+                    .map(|account| account.map(|account| account.data))
+                    .collect(),
+                Err(err) => {
+                    log::warn!("Cannot fetch {} accounts: {}", chunk.len(), err);
+                    vec![None; chunk.len()]
+                }
+            })
+            .collect()
+    }
+    fn account_data_around(&self, _pubkey: &Pubkey, _slot: u64) -> Option<(Vec<u8>, Vec<u8>)> {
+        None
+    }
+}
+
+/// Accounts kept in memory, for tests.
+#[derive(Debug, Default)]
+pub struct MemorySource {
+    accounts: RwLock<HashMap<Pubkey, BTreeMap<u64, Vec<u8>>>>,
+}
+
+impl MemorySource {
+    /// Set the data of `pubkey` from `slot` on.
+    pub fn insert(&self, pubkey: Pubkey, slot: u64, data: Vec<u8>) {
+        self.accounts
+            .write()
+            .unwrap()
+            .entry(pubkey)
+            .or_default()
+            .insert(slot, data);
+    }
+}
+
+impl AccountSource for MemorySource {
+    fn account_data(&self, pubkey: &Pubkey, slot: u64) -> Option<Vec<u8>> {
+        self.accounts
+            .read()
+            .unwrap()
+            .get(pubkey)?
+            .range(..=slot)
+            .next_back()
+            .map(|(_, data)| data.clone())
+    }
+}
+
+/// Data fetched for each account and slot, `None` when the source has none.
+type AccountCache = RefCell<HashMap<(Pubkey, u64), Option<Vec<u8>>>>;
+
+/// Accounts read while indexing a block, each fetched once per slot and decoded on demand.
+pub struct AccountInfos {
+    source: Option<Arc<dyn AccountSource>>,
+    cache: AccountCache,
+}
+
+impl AccountInfos {
+    pub fn new(source: Option<Arc<dyn AccountSource>>) -> Self {
+        AccountInfos {
+            source,
+            cache: RefCell::new(HashMap::new()),
+        }
+    }
+    pub fn is_available(&self) -> bool {
+        self.source.is_some()
+    }
+    /// Fetch the accounts not read yet in one request.
+    pub fn prefetch(&self, pubkeys: &[Pubkey], slot: u64) {
+        let source = match self.source.as_ref() {
+            Some(source) => source,
+            None => return,
+        };
+        let mut missing: Vec<Pubkey> = pubkeys
+            .iter()
+            .filter(|pubkey| !self.cache.borrow().contains_key(&(**pubkey, slot)))
+            .cloned()
+            .collect();
+        missing.sort();
+        missing.dedup();
+        if missing.is_empty() {
+            return;
+        }
+        let data = source.multiple_account_data(&missing, slot);
+        let mut cache = self.cache.borrow_mut();
+        for (pubkey, data) in missing.into_iter().zip(data) {
+            cache.insert((pubkey, slot), data);
+        }
+    }
+    /// Data of `pubkey` at the end of `slot`, `None` for unknown or closed accounts.
+    pub fn data(&self, pubkey: &Pubkey, slot: u64) -> Option<Vec<u8>> {
+        self.prefetch(&[*pubkey], slot);
+        self.cache
+            .borrow()
+            .get(&(*pubkey, slot))
+            .cloned()
+            .flatten()
+            .filter(|data| !data.is_empty())
+    }
+    fn decode<T>(
+        &self,
+        pubkey: &Pubkey,
+        slot: u64,
+        unpack: fn(&[u8]) -> Result<T, StateError>,
+    ) -> Result<Option<T>, StateError> {
+        self.data(pubkey, slot)
+            .map(|data| unpack(&data))
+            .transpose()
+    }
+    pub fn market_state(
+        &self,
+        pubkey: &Pubkey,
+        slot: u64,
+    ) -> Result<Option<MarketState>, StateError> {
+        self.decode(pubkey, slot, MarketState::unpack)
+    }
+    pub fn open_orders(
+        &self,
+        pubkey: &Pubkey,
+        slot: u64,
+    ) -> Result<Option<OpenOrders>, StateError> {
+        self.decode(pubkey, slot, OpenOrders::unpack)
+    }
+    pub fn slab(&self, pubkey: &Pubkey, slot: u64) -> Result<Option<Slab>, StateError> {
+        self.decode(pubkey, slot, Slab::unpack)
+    }
+    /// Account before and after `slot`. Not cached, the source decides whether it can tell
+    /// the two apart.
+    fn decode_around<T>(
+        &self,
+        pubkey: &Pubkey,
+        slot: u64,
+        unpack: fn(&[u8]) -> Result<T, StateError>,
+    ) -> Result<Option<(T, T)>, StateError> {
+        let source = match self.source.as_ref() {
+            Some(source) => source,
+            None => return Ok(None),
+        };
+        match source.account_data_around(pubkey, slot) {
+            Some((before, after)) => Ok(Some((unpack(&before)?, unpack(&after)?))),
+            None => Ok(None),
+        }
+    }
+    pub fn event_queue_around(
+        &self,
+        pubkey: &Pubkey,
+        slot: u64,
+    ) -> Result<Option<(EventQueue, EventQueue)>, StateError> {
+        self.decode_around(pubkey, slot, EventQueue::unpack)
+    }
+    pub fn request_queue_around(
+        &self,
+        pubkey: &Pubkey,
+        slot: u64,
+    ) -> Result<Option<(RequestQueue, RequestQueue)>, StateError> {
+        self.decode_around(pubkey, slot, RequestQueue::unpack)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AccountInfos, AccountSource, MemorySource, SnapshotDir};
+    use crate::testing::key;
+    use solana_program::pubkey::Pubkey;
+    use std::env;
+    use std::fs;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn snapshot_dir_reads_latest_capture_at_or_before_slot() {
+        let root = env::temp_dir().join(format!("serum-snapshots-{}", std::process::id()));
+        let dir = root.join(key(1).to_string());
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("10"), b"ten").unwrap();
+        fs::write(dir.join("20"), b"twenty").unwrap();
+        fs::write(dir.join("notes"), b"ignored").unwrap();
+        let source = SnapshotDir::new(&root);
+
+        assert_eq!(source.account_data(&key(1), 9), None);
+        assert_eq!(source.account_data(&key(1), 10), Some(b"ten".to_vec()));
+        assert_eq!(source.account_data(&key(1), 19), Some(b"ten".to_vec()));
+        assert_eq!(source.account_data(&key(1), 25), Some(b"twenty".to_vec()));
+        assert_eq!(source.account_data(&key(2), 25), None);
+        assert_eq!(
+            source.account_data_around(&key(1), 20),
+            Some((b"ten".to_vec(), b"twenty".to_vec()))
+        );
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn memory_source_serves_data_from_its_slot_on() {
+        let source = MemorySource::default();
+        source.insert(key(1), 5, vec![1]);
+        source.insert(key(1), 7, vec![2]);
+
+        assert_eq!(source.account_data(&key(1), 4), None);
+        assert_eq!(source.account_data(&key(1), 6), Some(vec![1]));
+        assert_eq!(
+            source.multiple_account_data(&[key(1), key(2)], 8),
+            vec![Some(vec![2]), None]
+        );
+        assert_eq!(source.account_data_around(&key(1), 5), None);
+    }
+
+    /// Source recording the accounts of every request.
+    #[derive(Default)]
+    struct CountingSource {
+        memory: MemorySource,
+        requests: Mutex<Vec<Vec<Pubkey>>>,
+    }
+
+    impl AccountSource for CountingSource {
+        fn account_data(&self, pubkey: &Pubkey, slot: u64) -> Option<Vec<u8>> {
+            self.memory.account_data(pubkey, slot)
+        }
+        fn multiple_account_data(&self, pubkeys: &[Pubkey], slot: u64) -> Vec<Option<Vec<u8>>> {
+            self.requests.lock().unwrap().push(pubkeys.to_vec());
+            self.memory.multiple_account_data(pubkeys, slot)
+        }
+    }
+
+    #[test]
+    fn account_infos_fetch_each_account_once_per_slot() {
+        let source = Arc::new(CountingSource::default());
+        source.memory.insert(key(1), 1, vec![1]);
+        source.memory.insert(key(2), 1, Vec::new());
+        let account_infos = AccountInfos::new(Some(source.clone()));
+
+        account_infos.prefetch(&[key(2), key(1), key(2), key(3)], 1);
+        assert_eq!(account_infos.data(&key(1), 1), Some(vec![1]));
+        // Closed accounts have empty data, unknown ones none
+        assert_eq!(account_infos.data(&key(2), 1), None);
+        assert_eq!(account_infos.data(&key(3), 1), None);
+        assert_eq!(account_infos.data(&key(1), 2), Some(vec![1]));
+        let mut first = vec![key(1), key(2), key(3)];
+        first.sort();
+        assert_eq!(*source.requests.lock().unwrap(), vec![first, vec![key(1)]]);
+
+        let unavailable = AccountInfos::new(None);
+        assert!(!unavailable.is_available());
+        assert_eq!(unavailable.data(&key(1), 1), None);
+    }
+}
@@ -0,0 +1,410 @@
+use crate::derived::journal::Journal;
+use crate::{derived, fork, metrics, STORE};
+use lazy_static::lazy_static;
+use massbit_solana_sdk::entity::{Entity, Value};
+use massbit_solana_sdk::types::SolanaBlock;
+use std::env;
+use std::error::Error;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::Instant;
+
+lazy_static! {
+    /// Entities saved by the handlers since the last commit, with the slot of the block that
+    /// saved them when known.
+    static ref PENDING: Mutex<Vec<(Option<u64>, String, Entity)>> = Mutex::new(Vec::new());
+    /// Slot of the block being indexed.
+    static ref CURRENT_SLOT: Mutex<Option<u64>> = Mutex::new(None);
+    /// Slot a failing call reverts derived state to: the last commit, or the fork point of a
+    /// rollback whose rows are still pending when that is lower.
+    static ref SETTLED: Mutex<Option<u64>> = Mutex::new(None);
+    /// Entity name and id of the committed rows, by the slot that saved them.
+    static ref COMMITTED: Mutex<Journal<(String, String), ()>> = Mutex::new(Journal::default());
+}
+
+/// Entities that record reorganizations, never dropped by one.
+const REORG_ENTITIES: [&str; 2] = ["Rollback", "Tombstone"];
+
+/// When the entities buffered by the handlers are written to the store.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FlushMode {
+    /// Commit after every block.
+    Block,
+    /// Commit once per `handle_blocks` call. A failing block discards the entities and
+    /// derived state of the whole call, so the store never holds part of a batch. The rows of
+    /// a rollback go with the commit of the call too.
+    Batch,
+}
+impl FromStr for FlushMode {
+    type Err = String;
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().as_str() {
+            "block" => Ok(FlushMode::Block),
+            "batch" => Ok(FlushMode::Batch),
+            _ => Err(format!("Unknown flush mode {}", value)),
+        }
+    }
+}
+
+/// Read `SERUM_FLUSH_MODE`, `block` unless set to `batch`.
+pub fn load_flush_mode() -> FlushMode {
+    env::var("SERUM_FLUSH_MODE")
+        .ok()
+        .and_then(|value| {
+            value
+                .parse()
+                .map_err(|err| log::warn!("Ignore SERUM_FLUSH_MODE: {}", err))
+                .ok()
+        })
+        .unwrap_or(FlushMode::Block)
+}
+
+pub fn push(entity_name: &str, entity: Entity) {
+    let slot = *CURRENT_SLOT.lock().unwrap();
+    PENDING
+        .lock()
+        .unwrap()
+        .push((slot, entity_name.to_string(), entity));
+}
+
+/// Drop the entities saved since the last commit.
+pub fn discard() {
+    PENDING.lock().unwrap().clear();
+    *CURRENT_SLOT.lock().unwrap() = None;
+    metrics::discard();
+}
+
+/// Drop the entities saved since the last commit by the blocks above `slot`.
+fn discard_above(slot: u64) {
+    PENDING
+        .lock()
+        .unwrap()
+        .retain(|(saved, _, _)| saved.map(|saved| saved <= slot).unwrap_or(true));
+    metrics::discard_above(slot);
+}
+
+/// Hand the pending entities to the store and flush them as one commit up to `block_slot`.
+pub fn commit(block_hash: &String, block_slot: u64) -> Result<(), Box<dyn Error>> {
+    let entities = std::mem::take(&mut *PENDING.lock().unwrap());
+    *CURRENT_SLOT.lock().unwrap() = None;
+    let store =
+        unsafe { (*std::ptr::addr_of_mut!(STORE)).as_mut() }.ok_or("Store is not initialized")?;
+    let mut rows: Vec<(u64, (String, String))> = Vec::new();
+    for (slot, entity_name, entity) in entities {
+        let slot = slot.unwrap_or(block_slot);
+        metrics::count_entity(slot, &entity_name);
+        if let (false, Some(Value::String(id))) = (
+            REORG_ENTITIES.contains(&entity_name.as_str()),
+            entity.get("id"),
+        ) {
+            rows.push((slot, (entity_name.clone(), id.clone())));
+        }
+        store.save(entity_name, entity);
+    }
+    store.flush(block_hash, block_slot).map_err(|err| {
+        format!(
+            "Cannot commit block {} ({}): {}",
+            block_slot, block_hash, err
+        )
+    })?;
+    let mut committed = COMMITTED.lock().unwrap();
+    for (slot, row) in rows {
+        committed.record(slot, row, None);
+    }
+    metrics::commit();
+    *SETTLED.lock().unwrap() = Some(block_slot);
+    Ok(())
+}
+
+/// Entity name and id of the rows committed by the blocks above `slot`, each once.
+pub fn committed_above(slot: u64) -> Vec<(String, String)> {
+    let mut rows: Vec<(String, String)> = COMMITTED
+        .lock()
+        .unwrap()
+        .rollback(slot)
+        .into_iter()
+        .map(|(row, _)| row)
+        .collect();
+    rows.sort();
+    rows.dedup();
+    rows
+}
+
+/// Undo the blocks above `slot` after a reorganization replaced them with a fork whose block
+/// at `slot` is `block_hash`: their pending entities are dropped, their committed rows are
+/// restored or tombstoned by `derived::rollback`. The result is committed at `slot` in
+/// `Block` mode and left pending in `Batch` mode, where a failure later in the call keeps it
+/// for the next commit.
+fn rollback(slot: u64, block_hash: &String, mode: FlushMode) -> Result<(), Box<dyn Error>> {
+    log::warn!("Roll back derived state to slot {} ({})", slot, block_hash);
+    discard_above(slot);
+    *CURRENT_SLOT.lock().unwrap() = Some(slot);
+    derived::rollback(slot, block_hash);
+    fork::truncate(slot);
+    match mode {
+        FlushMode::Block => commit(block_hash, slot),
+        FlushMode::Batch => {
+            // Committed rows above `slot` are tombstoned by now, derived state cannot go back
+            // above it
+            if let Some(settled) = SETTLED.lock().unwrap().as_mut() {
+                *settled = (*settled).min(slot);
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Index `blocks` with `handle_block`, committing as `mode` says. Returns the slot of the
+/// last committed block, `-1` when none was.
+///
+/// A block that does not extend the indexed chain rolls the blocks it replaces back first.
+/// When a block fails, the entities of the blocks not committed yet are dropped and derived
+/// state is reverted to the last committed slot, the fork point of a pending rollback, or the
+/// slot before the first block when nothing was committed yet, so that the failed blocks can
+/// be indexed again. The error tells the slots committed before the failure.
+pub fn index_blocks(
+    blocks: &[SolanaBlock],
+    mode: FlushMode,
+    handle_block: fn(&SolanaBlock) -> Result<(), Box<dyn Error>>,
+) -> Result<i64, Box<dyn Error>> {
+    let mut block_slot = -1_i64;
+    if let Err(err) = commit_blocks(blocks, mode, handle_block, &mut block_slot) {
+        let first = blocks.iter().map(|block| block.block_number).min();
+        let settled = *SETTLED.lock().unwrap();
+        match settled.or_else(|| first.map(|first| first.saturating_sub(1))) {
+            Some(slot) => {
+                discard_above(slot);
+                *CURRENT_SLOT.lock().unwrap() = None;
+                derived::revert(slot);
+                fork::truncate(slot);
+            }
+            None => discard(),
+        }
+        if block_slot < 0 {
+            return Err(err);
+        }
+        return Err(format!(
+            "{}, the blocks up to slot {} are committed",
+            err, block_slot
+        )
+        .into());
+    }
+    Ok(block_slot)
+}
+
+/// Index and commit `blocks`, `block_slot` is the slot of the last commit.
+fn commit_blocks(
+    blocks: &[SolanaBlock],
+    mode: FlushMode,
+    handle_block: fn(&SolanaBlock) -> Result<(), Box<dyn Error>>,
+    block_slot: &mut i64,
+) -> Result<(), Box<dyn Error>> {
+    for block in blocks {
+        if let Some((slot, block_hash)) = fork::fork_point(block)? {
+            rollback(slot, &block_hash, mode)?;
+            if mode == FlushMode::Block {
+                *block_slot = slot as i64;
+            }
+        }
+        let started = Instant::now();
+        *CURRENT_SLOT.lock().unwrap() = Some(block.block_number);
+        handle_block(block)?;
+        fork::extend(block);
+        metrics::observe_block(
+            block.block_number,
+            block.block.block_time,
+            started.elapsed(),
+        );
+        if mode == FlushMode::Block {
+            commit(&block.block.blockhash, block.block_number)?;
+            *block_slot = (*block_slot).max(block.block_number as i64);
+        }
+    }
+    if mode == FlushMode::Batch {
+        if let Some(last) = blocks.iter().max_by_key(|block| block.block_number) {
+            commit(&last.block.blockhash, last.block_number)?;
+            *block_slot = last.block_number as i64;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{index_blocks, FlushMode};
+    use crate::account_source::AccountInfos;
+    use crate::derived;
+    use crate::derived::order::ORDERS;
+    use crate::generated::instruction::*;
+    use crate::testing::{block, ctx, fail_flush, key, lock_state, stored};
+    use massbit_solana_sdk::entity::Value;
+    use massbit_solana_sdk::types::SolanaBlock;
+    use solana_program::message::Message;
+    use std::error::Error;
+    use std::num::NonZeroU64;
+
+    /// Slots `place_orders` fails at.
+    const FAILING_SLOTS: &[u64] = &[502, 803, 902];
+
+    /// Place an order with the slot as client id, cancel the order of the previous slot and
+    /// fail at `FAILING_SLOTS`.
+    fn place_orders(block: &SolanaBlock) -> Result<(), Box<dyn Error>> {
+        let slot = block.block_number;
+        let (market, open_orders, event_queue) = (key(100), key(101), key(103));
+        let place = MarketInstruction::NewOrderV3(NewOrderInstructionV3 {
+            side: Side::Bid,
+            limit_price: NonZeroU64::new(50).unwrap(),
+            max_coin_qty: NonZeroU64::new(1).unwrap(),
+            max_native_pc_qty_including_fees: NonZeroU64::new(100).unwrap(),
+            self_trade_behavior: SelfTradeBehavior::DecrementTake,
+            order_type: OrderType::Limit,
+            client_order_id: slot,
+            limit: 10,
+        });
+        let cancel = MarketInstruction::CancelOrderByClientIdV2(slot - 1);
+        for (instruction_index, (instruction, accounts)) in [
+            (place, vec![market, open_orders, key(102), event_queue]),
+            (
+                cancel,
+                vec![market, key(104), key(105), open_orders, event_queue],
+            ),
+        ]
+        .iter()
+        .enumerate()
+        {
+            derived::process_instruction(
+                &ctx(slot, instruction_index),
+                &AccountInfos::new(None),
+                &Message::default(),
+                accounts,
+                instruction,
+            )?;
+        }
+        if FAILING_SLOTS.contains(&slot) {
+            return Err(format!("Cannot index block {}", slot).into());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn failed_batch_reverts_derived_state() {
+        let _state = lock_state();
+        // Without account data order ids are unknown, orders keep the id of their instruction
+        let id = |slot: u64| ctx(slot, 0).entity_id();
+        index_blocks(&[block(500)], FlushMode::Block, place_orders).unwrap();
+        let committed = ORDERS.lock().unwrap().get(&id(500)).cloned();
+        assert!(committed.is_some());
+
+        let blocks = [block(501), block(502), block(503)];
+        assert!(index_blocks(&blocks, FlushMode::Batch, place_orders).is_err());
+
+        let orders = ORDERS.lock().unwrap();
+        assert_eq!(orders.get(&id(500)).cloned(), committed);
+        assert!(orders.get(&id(501)).is_none());
+        assert!(orders.get(&id(502)).is_none());
+        assert!(!stored("Order").contains_key(&id(501)));
+    }
+
+    #[test]
+    fn fork_rolls_back_replaced_blocks() {
+        let _state = lock_state();
+        let id = |slot: u64| ctx(slot, 0).entity_id();
+        index_blocks(&[block(600), block(601)], FlushMode::Block, place_orders).unwrap();
+        assert!(ORDERS.lock().unwrap().get(&id(600)).is_none());
+
+        // Slot 601 is replaced by a fork that skips it
+        let mut fork = block(602);
+        fork.block.parent_slot = 600;
+        fork.block.previous_blockhash = "hash-600".to_string();
+        assert_eq!(
+            index_blocks(&[fork], FlushMode::Block, place_orders).unwrap(),
+            602
+        );
+
+        let orders = ORDERS.lock().unwrap();
+        assert!(orders.get(&id(600)).is_some());
+        assert!(orders.get(&id(601)).is_none());
+        assert!(orders.get(&id(602)).is_some());
+        let stored_orders = stored("Order");
+        assert_eq!(
+            stored_orders[&id(600)].get("status"),
+            Some(&Value::from("open"))
+        );
+        let tombstones: Vec<Value> = stored("Tombstone")
+            .values()
+            .filter_map(|tombstone| tombstone.get("entity_id").cloned())
+            .collect();
+        assert_eq!(tombstones, vec![Value::from(id(601))]);
+        assert!(stored("Rollback").contains_key("600-hash-600"));
+    }
+
+    #[test]
+    fn fork_in_batch_commits_with_the_batch() {
+        let _state = lock_state();
+        let id = |slot: u64| ctx(slot, 0).entity_id();
+        index_blocks(&[block(800), block(801)], FlushMode::Block, place_orders).unwrap();
+        let cancelled = stored("Order")[&id(800)].get("status").cloned();
+
+        // Slot 801 is replaced by a fork, the batch fails after the rollback
+        let mut fork = block(802);
+        fork.block.parent_slot = 800;
+        fork.block.previous_blockhash = "hash-800".to_string();
+        let blocks = [fork.clone(), block(803)];
+        assert!(index_blocks(&blocks, FlushMode::Batch, place_orders).is_err());
+        assert!(stored("Rollback").is_empty());
+        assert!(stored("Tombstone").is_empty());
+        assert!(!stored("Order").contains_key(&id(802)));
+        assert_eq!(stored("Order")[&id(800)].get("status").cloned(), cancelled);
+
+        // The rollback is committed with the next batch
+        assert_eq!(
+            index_blocks(&[fork], FlushMode::Batch, place_orders).unwrap(),
+            802
+        );
+        let orders = ORDERS.lock().unwrap();
+        assert!(orders.get(&id(800)).is_some());
+        assert!(orders.get(&id(801)).is_none());
+        assert!(orders.get(&id(802)).is_some());
+        let stored_orders = stored("Order");
+        assert_eq!(
+            stored_orders[&id(800)].get("status"),
+            Some(&Value::from("open"))
+        );
+        assert!(stored_orders.contains_key(&id(802)));
+        let tombstones: Vec<Value> = stored("Tombstone")
+            .values()
+            .filter_map(|tombstone| tombstone.get("entity_id").cloned())
+            .collect();
+        assert_eq!(tombstones, vec![Value::from(id(801))]);
+        assert!(stored("Rollback").contains_key("800-hash-800"));
+    }
+
+    #[test]
+    fn failed_block_reports_committed_slots() {
+        let _state = lock_state();
+        let blocks = [block(900), block(901), block(902)];
+        let err = index_blocks(&blocks, FlushMode::Block, place_orders).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Cannot index block 902, the blocks up to slot 901 are committed"
+        );
+        assert!(stored("Order").contains_key(&ctx(901, 0).entity_id()));
+        assert!(!stored("Order").contains_key(&ctx(902, 0).entity_id()));
+    }
+
+    #[test]
+    fn failed_commit_names_the_block() {
+        let _state = lock_state();
+        fail_flush(951);
+        let err = index_blocks(&[block(951)], FlushMode::Batch, place_orders).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Cannot commit block 951 (hash-951): Store is down"
+        );
+        assert!(ORDERS
+            .lock()
+            .unwrap()
+            .get(&ctx(951, 0).entity_id())
+            .is_none());
+    }
+}
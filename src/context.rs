@@ -0,0 +1,152 @@
+use crate::program::DexVersion;
+use massbit_solana_sdk::entity::{Attribute, Value};
+use massbit_solana_sdk::types::SolanaBlock;
+use solana_program::pubkey::Pubkey;
+use solana_transaction_status::TransactionWithStatusMeta;
+use std::collections::HashMap;
+
+/// Location of a Serum instruction inside a block. Entity ids are derived from it so that
+/// replaying a block range writes the same rows again instead of duplicating them, and every
+/// entity carries its fields as a provenance header.
+#[derive(Clone, Debug, PartialEq)]
+pub struct InstructionContext {
+    pub program_id: Pubkey,
+    pub dex_version: DexVersion,
+    pub slot: u64,
+    pub block_hash: String,
+    pub block_time: Option<i64>,
+    pub signature: String,
+    pub fee_payer: String,
+    pub error: Option<String>,
+    pub tx_index: usize,
+    pub instruction_index: usize,
+    pub inner_index: Option<usize>,
+    pub invoking_program: Option<Pubkey>,
+    pub instruction_version: Option<u8>,
+}
+
+impl InstructionContext {
+    pub fn new(
+        block: &SolanaBlock,
+        transaction: &TransactionWithStatusMeta,
+        tx_index: usize,
+        instruction_index: usize,
+        program_id: &Pubkey,
+        dex_version: DexVersion,
+    ) -> Self {
+        InstructionContext {
+            program_id: *program_id,
+            dex_version,
+            slot: block.block_number,
+            block_hash: block.block.blockhash.clone(),
+            block_time: block.block.block_time,
+            signature: transaction
+                .transaction
+                .signatures
+                .first()
+                .map(|signature| signature.to_string())
+                .unwrap_or_default(),
+            fee_payer: transaction
+                .transaction
+                .message
+                .account_keys
+                .first()
+                .map(|pubkey| pubkey.to_string())
+                .unwrap_or_default(),
+            error: transaction
+                .meta
+                .as_ref()
+                .and_then(|meta| meta.status.as_ref().err())
+                .map(|err| err.to_string()),
+            tx_index,
+            instruction_index,
+            inner_index: None,
+            invoking_program: None,
+            instruction_version: None,
+        }
+    }
+    pub fn with_instruction_version(&self, version: u8) -> Self {
+        InstructionContext {
+            instruction_version: Some(version),
+            ..self.clone()
+        }
+    }
+    /// `<signature>-<instruction_index>` for top level instructions and
+    /// `<signature>-<instruction_index>-<inner_index>` for instructions invoked through CPI.
+    pub fn entity_id(&self) -> String {
+        match self.inner_index {
+            Some(inner_index) => format!(
+                "{}-{}-{}",
+                self.signature, self.instruction_index, inner_index
+            ),
+            None => format!("{}-{}", self.signature, self.instruction_index),
+        }
+    }
+    /// `key=value` fields leading the log records of an instruction, so they can be filtered
+    /// by slot, transaction, instruction type or market.
+    pub fn log_fields(&self, instruction: &str, market: Option<&Pubkey>) -> String {
+        format!(
+            "slot={} signature={} instruction={} market={}",
+            self.slot,
+            self.signature,
+            instruction,
+            market
+                .map(|market| market.to_string())
+                .unwrap_or_else(|| "-".to_string())
+        )
+    }
+    pub fn insert_header(&self, map: &mut HashMap<Attribute, Value>) {
+        map.insert(
+            "program_id".to_string(),
+            Value::from(self.program_id.to_string()),
+        );
+        map.insert(
+            "dex_version".to_string(),
+            Value::from(self.dex_version.as_str().to_string()),
+        );
+        map.insert("slot".to_string(), Value::from(self.slot));
+        map.insert(
+            "block_hash".to_string(),
+            Value::from(self.block_hash.clone()),
+        );
+        map.insert(
+            "timestamp".to_string(),
+            self.block_time
+                .map(|block_time| Value::from(block_time as u64))
+                .unwrap_or(Value::Null),
+        );
+        map.insert("signature".to_string(), Value::from(self.signature.clone()));
+        map.insert("fee_payer".to_string(), Value::from(self.fee_payer.clone()));
+        map.insert("success".to_string(), Value::from(self.error.is_none()));
+        map.insert(
+            "error".to_string(),
+            self.error
+                .as_ref()
+                .map(|err| Value::from(err.clone()))
+                .unwrap_or(Value::Null),
+        );
+        map.insert("tx_index".to_string(), Value::from(self.tx_index as u64));
+        map.insert(
+            "instruction_index".to_string(),
+            Value::from(self.instruction_index as u64),
+        );
+        map.insert(
+            "inner_index".to_string(),
+            self.inner_index
+                .map(|inner_index| Value::from(inner_index as u64))
+                .unwrap_or(Value::Null),
+        );
+        map.insert(
+            "invoking_program".to_string(),
+            self.invoking_program
+                .map(|program| Value::from(program.to_string()))
+                .unwrap_or(Value::Null),
+        );
+        map.insert(
+            "instruction_version".to_string(),
+            self.instruction_version
+                .map(Value::from)
+                .unwrap_or(Value::Null),
+        );
+    }
+}
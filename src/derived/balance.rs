@@ -0,0 +1,233 @@
+use crate::account_source::AccountInfos;
+use crate::context::InstructionContext;
+use crate::generated::handler::EntityExt;
+use crate::generated::instruction::MarketInstruction;
+use crate::state::open_orders::OpenOrders;
+use massbit_solana_sdk::entity::{Attribute, Entity, Value};
+use solana_program::message::Message;
+use solana_program::pubkey::Pubkey;
+use std::collections::HashMap;
+
+/// Open orders accounts passed to an instruction.
+pub fn open_orders_accounts(instruction: &MarketInstruction, accounts: &[Pubkey]) -> Vec<Pubkey> {
+    let mut open_orders: Vec<Pubkey> = match instruction {
+        // Open orders accounts come first, the market accounts are at the end
+        MarketInstruction::ConsumeEvents(_) => accounts
+            .get(..accounts.len().saturating_sub(4))
+            .unwrap_or_default()
+            .to_vec(),
+        MarketInstruction::ConsumeEventsPermissioned(_) => accounts
+            .get(..accounts.len().saturating_sub(3))
+            .unwrap_or_default()
+            .to_vec(),
+        _ => instruction
+            .open_orders_account()
+            .and_then(|index| accounts.get(index))
+            .into_iter()
+            .cloned()
+            .collect(),
+    };
+    open_orders.sort();
+    open_orders.dedup();
+    open_orders
+}
+
+/// Whether the message locks `pubkey` for writing. Account keys are ordered as writable
+/// signers, read-only signers, writable non signers and read-only non signers, the header
+/// gives the size of the read-only groups.
+pub fn is_writable(message: &Message, pubkey: &Pubkey) -> bool {
+    let index = match message.account_keys.iter().position(|key| key == pubkey) {
+        Some(index) => index,
+        None => return false,
+    };
+    let header = &message.header;
+    let signers = header.num_required_signatures as usize;
+    if index < signers {
+        index < signers.saturating_sub(header.num_readonly_signed_accounts as usize)
+    } else {
+        index
+            < message
+                .account_keys
+                .len()
+                .saturating_sub(header.num_readonly_unsigned_accounts as usize)
+    }
+}
+
+/// Save the balances of the open orders accounts an instruction may have changed, read at the
+/// end of the instruction slot.
+pub fn process_instruction(
+    ctx: &InstructionContext,
+    account_infos: &AccountInfos,
+    message: &Message,
+    accounts: &[Pubkey],
+    instruction: &MarketInstruction,
+) -> Result<(), anyhow::Error> {
+    if !account_infos.is_available() {
+        return Ok(());
+    }
+    let open_orders: Vec<Pubkey> = open_orders_accounts(instruction, accounts)
+        .into_iter()
+        .filter(|open_orders| is_writable(message, open_orders))
+        .collect();
+    account_infos.prefetch(&open_orders, ctx.slot);
+    for open_orders in open_orders {
+        // Closed accounts have no data left
+        if let Some(state) = account_infos.open_orders(&open_orders, ctx.slot)? {
+            save_balance(ctx, &open_orders, &state);
+        }
+    }
+    Ok(())
+}
+
+fn save_balance(ctx: &InstructionContext, open_orders: &Pubkey, state: &OpenOrders) {
+    Entity::from(balance_attributes(ctx, open_orders, state)).save("OpenOrdersBalance");
+}
+
+pub fn balance_attributes(
+    ctx: &InstructionContext,
+    open_orders: &Pubkey,
+    state: &OpenOrders,
+) -> HashMap<Attribute, Value> {
+    let mut map: HashMap<Attribute, Value> = HashMap::default();
+    map.insert(
+        "id".to_string(),
+        Value::from(format!("{}-{}", open_orders, ctx.slot)),
+    );
+    ctx.insert_header(&mut map);
+    map.insert(
+        "open_orders".to_string(),
+        Value::from(open_orders.to_string()),
+    );
+    map.insert("market".to_string(), Value::from(state.market.to_string()));
+    map.insert("owner".to_string(), Value::from(state.owner.to_string()));
+    map.insert(
+        "native_coin_free".to_string(),
+        Value::from(state.native_coin_free),
+    );
+    map.insert(
+        "native_coin_locked".to_string(),
+        Value::from(state.native_coin_locked()),
+    );
+    map.insert(
+        "native_coin_total".to_string(),
+        Value::from(state.native_coin_total),
+    );
+    map.insert(
+        "native_pc_free".to_string(),
+        Value::from(state.native_pc_free),
+    );
+    map.insert(
+        "native_pc_locked".to_string(),
+        Value::from(state.native_pc_locked()),
+    );
+    map.insert(
+        "native_pc_total".to_string(),
+        Value::from(state.native_pc_total),
+    );
+    map.insert(
+        "order_count".to_string(),
+        Value::from(state.iter_orders().count() as u64),
+    );
+    map.insert(
+        "referrer_rebates_accrued".to_string(),
+        state
+            .referrer_rebates_accrued
+            .map(Value::from)
+            .unwrap_or(Value::Null),
+    );
+    map
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::account_source::{AccountInfos, MemorySource};
+    use crate::batch;
+    use crate::derived;
+    use crate::generated::instruction::{MarketInstruction, Side};
+    use crate::state::event_queue::{event_flag, Event};
+    use crate::testing::{ctx, event_queue_data, key, lock_state, open_orders_data, stored};
+    use massbit_solana_sdk::entity::Value;
+    use solana_program::message::{Message, MessageHeader};
+    use solana_program::pubkey::Pubkey;
+    use std::sync::Arc;
+
+    fn maker_fill(owner: Pubkey, order_id: u128) -> Event {
+        Event {
+            event_flags: event_flag::FILL | event_flag::MAKER,
+            owner_slot: 0,
+            fee_tier: 0,
+            native_qty_released: 500,
+            native_qty_paid: 10,
+            native_fee_or_rebate: 0,
+            order_id,
+            owner,
+            client_order_id: 0,
+        }
+    }
+
+    #[test]
+    fn crank_saves_fills_and_balances_from_account_data() {
+        let _state = lock_state();
+        let (market, event_queue, open_orders_a, open_orders_b) =
+            (key(90), key(91), key(92), key(93));
+        let source = MemorySource::default();
+        let events = [
+            maker_fill(open_orders_a, 50 << 64 | 1),
+            maker_fill(open_orders_b, 50 << 64 | 2),
+        ];
+        source.insert(event_queue, 399, event_queue_data(52, &events));
+        source.insert(event_queue, 400, event_queue_data(52, &[]));
+        source.insert(open_orders_a, 400, open_orders_data(market, key(94), &[]));
+        source.insert(
+            open_orders_b,
+            400,
+            open_orders_data(market, key(95), &[(Side::Ask, 50 << 64 | 3, 0)]),
+        );
+        let account_infos = AccountInfos::new(Some(Arc::new(source)));
+        // Only accounts the transaction locks for writing can have changed
+        let message = Message {
+            header: MessageHeader {
+                num_required_signatures: 1,
+                num_readonly_signed_accounts: 0,
+                num_readonly_unsigned_accounts: 0,
+            },
+            account_keys: vec![key(2), open_orders_a, open_orders_b],
+            ..Message::default()
+        };
+
+        derived::process_instruction(
+            &ctx(400, 0),
+            &account_infos,
+            &message,
+            &[
+                open_orders_a,
+                open_orders_b,
+                market,
+                event_queue,
+                key(96),
+                key(97),
+            ],
+            &MarketInstruction::ConsumeEvents(5),
+        )
+        .unwrap();
+        batch::commit(&"hash-400".to_string(), 400).unwrap();
+
+        let fills = stored("Fill");
+        for (seq_num, open_orders) in [(50, open_orders_a), (51, open_orders_b)] {
+            let fill = &fills[&format!("{}-{}", event_queue, seq_num)];
+            assert_eq!(
+                fill.get("open_orders"),
+                Some(&Value::from(open_orders.to_string()))
+            );
+        }
+        let balances = stored("OpenOrdersBalance");
+        assert_eq!(balances.len(), 2);
+        let order_count = |open_orders: Pubkey| {
+            balances[&format!("{}-400", open_orders)]
+                .get("order_count")
+                .cloned()
+        };
+        assert_eq!(order_count(open_orders_a), Some(Value::from(0u64)));
+        assert_eq!(order_count(open_orders_b), Some(Value::from(1u64)));
+    }
+}
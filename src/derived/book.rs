@@ -0,0 +1,350 @@
+use crate::account_source::AccountInfos;
+use crate::context::InstructionContext;
+use crate::derived::journal::Journal;
+use crate::generated::handler::EntityExt;
+use crate::generated::instruction::MarketInstruction;
+use crate::state::market::MarketState;
+use crate::state::slab::LeafNode;
+use crate::ORDER_BOOK;
+use lazy_static::lazy_static;
+use massbit_solana_sdk::entity::{Attribute, Entity, Value};
+use solana_program::pubkey::Pubkey;
+use std::collections::HashMap;
+use std::env;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+lazy_static! {
+    /// Slot of the last snapshot of each market, the book is captured once per slot.
+    static ref SNAPSHOT_SLOTS: Mutex<SnapshotSlots> = Mutex::new(SnapshotSlots::default());
+}
+
+#[derive(Default)]
+struct SnapshotSlots {
+    slots: HashMap<Pubkey, u64>,
+    journal: Journal<Pubkey, u64>,
+}
+impl SnapshotSlots {
+    fn insert(&mut self, market: Pubkey, slot: u64) {
+        let prior = self.slots.insert(market, slot);
+        self.journal.record(slot, market, prior);
+    }
+}
+
+/// Forget the snapshots taken above `slot`, so that the books of those slots are captured
+/// again.
+pub fn rollback(slot: u64) {
+    let mut snapshot_slots = SNAPSHOT_SLOTS.lock().unwrap();
+    for (market, prior) in snapshot_slots.journal.rollback(slot) {
+        match prior {
+            Some(prior) => snapshot_slots.slots.insert(market, prior),
+            None => snapshot_slots.slots.remove(&market),
+        };
+    }
+}
+
+/// Detail of the `OrderBookSnapshot` rows.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BookDetail {
+    /// Quantity by price level.
+    L2,
+    /// Price levels plus every resting order.
+    L3,
+}
+impl FromStr for BookDetail {
+    type Err = String;
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().as_str() {
+            "l2" => Ok(BookDetail::L2),
+            "l3" => Ok(BookDetail::L3),
+            _ => Err(format!("Unknown order book detail {}", value)),
+        }
+    }
+}
+
+/// Read `SERUM_ORDER_BOOK`, order books are not captured unless set to `l2` or `l3`.
+pub fn load_book_detail() -> Option<BookDetail> {
+    env::var("SERUM_ORDER_BOOK").ok().and_then(|value| {
+        value
+            .parse()
+            .map_err(|err| log::warn!("Ignore SERUM_ORDER_BOOK: {}", err))
+            .ok()
+    })
+}
+
+fn changes_book(instruction: &MarketInstruction) -> bool {
+    matches!(
+        instruction,
+        MarketInstruction::NewOrder(_)
+            | MarketInstruction::NewOrderV2(_)
+            | MarketInstruction::NewOrderV3(_)
+            | MarketInstruction::MatchOrders(_)
+            | MarketInstruction::CancelOrder(_)
+            | MarketInstruction::CancelOrderV2(_)
+            | MarketInstruction::CancelOrderByClientId(_)
+            | MarketInstruction::CancelOrderByClientIdV2(_)
+            | MarketInstruction::SendTake(_)
+            | MarketInstruction::Prune(_)
+    )
+}
+
+/// `(price_lots, quantity)` per price level, from the best price.
+pub fn levels(orders: &[&LeafNode]) -> Vec<(u64, u64)> {
+    let mut levels: Vec<(u64, u64)> = Vec::new();
+    for order in orders {
+        match levels.last_mut() {
+            Some((price, quantity)) if *price == order.price_lots() => *quantity += order.quantity,
+            _ => levels.push((order.price_lots(), order.quantity)),
+        }
+    }
+    levels
+}
+
+/// Capture the book of the market an instruction changed, from the bids and asks accounts at
+/// the end of the instruction slot.
+pub fn process_instruction(
+    ctx: &InstructionContext,
+    account_infos: &AccountInfos,
+    accounts: &[Pubkey],
+    instruction: &MarketInstruction,
+) -> Result<(), anyhow::Error> {
+    let detail = match *ORDER_BOOK {
+        Some(detail) => detail,
+        None => return Ok(()),
+    };
+    if !account_infos.is_available() || !changes_book(instruction) {
+        return Ok(());
+    }
+    let market = match instruction
+        .market_account()
+        .and_then(|index| accounts.get(index))
+    {
+        Some(market) => market,
+        None => return Ok(()),
+    };
+    if SNAPSHOT_SLOTS.lock().unwrap().slots.get(market) == Some(&ctx.slot) {
+        return Ok(());
+    }
+    let state = match account_infos.market_state(market, ctx.slot)? {
+        Some(state) => state,
+        None => return Ok(()),
+    };
+    account_infos.prefetch(&[state.bids, state.asks], ctx.slot);
+    let (bids, asks) = match (
+        account_infos.slab(&state.bids, ctx.slot)?,
+        account_infos.slab(&state.asks, ctx.slot)?,
+    ) {
+        (Some(bids), Some(asks)) => (bids, asks),
+        _ => return Ok(()),
+    };
+    save_snapshot(
+        ctx,
+        market,
+        &state,
+        &bids.orders()?,
+        &asks.orders()?,
+        detail,
+    );
+    // Only a saved snapshot counts, a later instruction of the slot retries a failed read
+    SNAPSHOT_SLOTS.lock().unwrap().insert(*market, ctx.slot);
+    Ok(())
+}
+
+fn save_snapshot(
+    ctx: &InstructionContext,
+    market: &Pubkey,
+    state: &MarketState,
+    bids: &[&LeafNode],
+    asks: &[&LeafNode],
+    detail: BookDetail,
+) {
+    Entity::from(snapshot_attributes(ctx, market, state, bids, asks, detail))
+        .save("OrderBookSnapshot");
+}
+
+pub fn snapshot_attributes(
+    ctx: &InstructionContext,
+    market: &Pubkey,
+    state: &MarketState,
+    bids: &[&LeafNode],
+    asks: &[&LeafNode],
+    detail: BookDetail,
+) -> HashMap<Attribute, Value> {
+    let bid_levels = levels(bids);
+    let ask_levels = levels(asks);
+    let column =
+        |values: Vec<u64>| Value::from(values.into_iter().map(Value::from).collect::<Vec<Value>>());
+    let mut map: HashMap<Attribute, Value> = HashMap::default();
+    map.insert(
+        "id".to_string(),
+        Value::from(format!("{}-{}", market, ctx.slot)),
+    );
+    ctx.insert_header(&mut map);
+    map.insert("market".to_string(), Value::from(market.to_string()));
+    map.insert("bids".to_string(), Value::from(state.bids.to_string()));
+    map.insert("asks".to_string(), Value::from(state.asks.to_string()));
+    map.insert(
+        "coin_lot_size".to_string(),
+        Value::from(state.coin_lot_size),
+    );
+    map.insert("pc_lot_size".to_string(), Value::from(state.pc_lot_size));
+    map.insert(
+        "best_bid".to_string(),
+        bid_levels
+            .first()
+            .map(|(price, _)| Value::from(*price))
+            .unwrap_or(Value::Null),
+    );
+    map.insert(
+        "best_ask".to_string(),
+        ask_levels
+            .first()
+            .map(|(price, _)| Value::from(*price))
+            .unwrap_or(Value::Null),
+    );
+    map.insert("bid_count".to_string(), Value::from(bids.len() as u64));
+    map.insert("ask_count".to_string(), Value::from(asks.len() as u64));
+    map.insert(
+        "bid_prices".to_string(),
+        column(bid_levels.iter().map(|(price, _)| *price).collect()),
+    );
+    map.insert(
+        "bid_sizes".to_string(),
+        column(bid_levels.iter().map(|(_, quantity)| *quantity).collect()),
+    );
+    map.insert(
+        "ask_prices".to_string(),
+        column(ask_levels.iter().map(|(price, _)| *price).collect()),
+    );
+    map.insert(
+        "ask_sizes".to_string(),
+        column(ask_levels.iter().map(|(_, quantity)| *quantity).collect()),
+    );
+    map.insert(
+        "orders".to_string(),
+        match detail {
+            BookDetail::L2 => Value::Null,
+            BookDetail::L3 => Value::from(
+                serde_json::to_string(&serde_json::json!({
+                    "bids": bids.iter().map(|order| order_json(order)).collect::<Vec<_>>(),
+                    "asks": asks.iter().map(|order| order_json(order)).collect::<Vec<_>>(),
+                }))
+                .unwrap_or_default(),
+            ),
+        },
+    );
+    map
+}
+
+fn order_json(order: &LeafNode) -> serde_json::Value {
+    serde_json::json!({
+        // Order ids do not fit in a JSON number
+        "order_id": order.key.to_string(),
+        "price_lots": order.price_lots(),
+        "quantity": order.quantity,
+        "open_orders": order.owner.to_string(),
+        "owner_slot": order.owner_slot,
+        "client_order_id": order.client_order_id,
+        "fee_tier": order.fee_tier,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::account_flag;
+    use crate::state::slab::{Slab, SlabHeader, SlabNode};
+    use crate::testing::{ctx, fixture, key, slab_data, MARKET_V3};
+
+    /// Side of a book with one order per `(price_lots, quantity)`, chained under inner nodes
+    /// from the lowest key.
+    fn slab(side_flag: u64, orders: &[(u64, u64)]) -> Slab {
+        let mut nodes = Vec::new();
+        for (seq_num, (price_lots, quantity)) in orders.iter().enumerate() {
+            let seq_num = match side_flag {
+                account_flag::BIDS => !(seq_num as u64),
+                _ => seq_num as u64,
+            };
+            nodes.push(SlabNode::Leaf(LeafNode {
+                owner_slot: 0,
+                fee_tier: 0,
+                key: (*price_lots as u128) << 64 | seq_num as u128,
+                owner: key(41),
+                quantity: *quantity,
+                client_order_id: 0,
+            }));
+        }
+        let mut leaves = (0..nodes.len() as u32).collect::<Vec<_>>();
+        leaves.sort_by_key(|index| match &nodes[*index as usize] {
+            SlabNode::Leaf(leaf) => leaf.key,
+            _ => unreachable!(),
+        });
+        let mut root = leaves.pop().unwrap();
+        while let Some(leaf) = leaves.pop() {
+            nodes.push(SlabNode::Inner {
+                prefix_len: 0,
+                key: 0,
+                children: [leaf, root],
+            });
+            root = nodes.len() as u32 - 1;
+        }
+        let header = SlabHeader {
+            account_flags: account_flag::INITIALIZED | side_flag,
+            bump_index: nodes.len() as u64,
+            free_list_len: 0,
+            free_list_head: 0,
+            root_node: root,
+            leaf_count: orders.len() as u64,
+        };
+        Slab::unpack(&slab_data(&header, &nodes)).unwrap()
+    }
+
+    fn column(values: &[u64]) -> Value {
+        Value::from(
+            values
+                .iter()
+                .map(|value| Value::from(*value))
+                .collect::<Vec<_>>(),
+        )
+    }
+
+    #[test]
+    fn sums_quantities_per_price_level() {
+        let bids = slab(account_flag::BIDS, &[(10, 1), (11, 20), (9, 4), (11, 5)]);
+        let asks = slab(account_flag::ASKS, &[(13, 2), (12, 3), (13, 7)]);
+        let (bids, asks) = (bids.orders().unwrap(), asks.orders().unwrap());
+        assert_eq!(levels(&bids), [(11, 25), (10, 1), (9, 4)]);
+        assert_eq!(levels(&asks), [(12, 3), (13, 9)]);
+        assert_eq!(levels(&[]), []);
+
+        let state = MarketState::unpack(&fixture(MARKET_V3)).unwrap();
+        let market = key(42);
+        let map = snapshot_attributes(&ctx(40, 0), &market, &state, &bids, &asks, BookDetail::L2);
+        assert_eq!(map["id"], Value::from(format!("{}-40", market)));
+        assert_eq!(map["best_bid"], Value::from(11u64));
+        assert_eq!(map["best_ask"], Value::from(12u64));
+        assert_eq!(map["bid_count"], Value::from(4u64));
+        assert_eq!(map["bid_prices"], column(&[11, 10, 9]));
+        assert_eq!(map["bid_sizes"], column(&[25, 1, 4]));
+        assert_eq!(map["ask_prices"], column(&[12, 13]));
+        assert_eq!(map["ask_sizes"], column(&[3, 9]));
+        assert_eq!(map["orders"], Value::Null);
+
+        // L3 lists every order, the older one first within a price
+        let map = snapshot_attributes(&ctx(40, 0), &market, &state, &bids, &asks, BookDetail::L3);
+        let orders: serde_json::Value = match &map["orders"] {
+            Value::String(orders) => serde_json::from_str(orders).unwrap(),
+            value => panic!("orders is {:?}", value),
+        };
+        let quantities = |side: &str| {
+            orders[side]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|order| order["quantity"].as_u64().unwrap())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(quantities("bids"), [20, 5, 1, 4]);
+        assert_eq!(quantities("asks"), [3, 2, 7]);
+    }
+}
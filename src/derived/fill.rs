@@ -0,0 +1,531 @@
+use crate::account_source::AccountInfos;
+use crate::context::InstructionContext;
+use crate::derived::balance::open_orders_accounts;
+use crate::derived::order;
+use crate::generated::handler::EntityExt;
+use crate::generated::instruction::{MarketInstruction, Side};
+use crate::order_id;
+use crate::state::event_queue::Event;
+use crate::state::request_queue::Request;
+use lazy_static::lazy_static;
+use massbit_solana_sdk::entity::{Attribute, Entity, Value};
+use solana_program::pubkey::Pubkey;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Market and event queue of the instructions that push or pop matching events.
+pub fn event_queue_accounts(
+    instruction: &MarketInstruction,
+    accounts: &[Pubkey],
+) -> Option<(Pubkey, Pubkey)> {
+    let (market, event_queue) = match instruction {
+        MarketInstruction::MatchOrders(_) => (0, 2),
+        MarketInstruction::NewOrderV3(_) => (0, 3),
+        MarketInstruction::CancelOrderV2(_) | MarketInstruction::CancelOrderByClientIdV2(_) => {
+            (0, 5)
+        }
+        MarketInstruction::Prune(_) => (0, 6),
+        // Open orders accounts come first, the market accounts are at the end
+        MarketInstruction::ConsumeEvents(_) => (
+            accounts.len().checked_sub(4)?,
+            accounts.len().checked_sub(3)?,
+        ),
+        MarketInstruction::ConsumeEventsPermissioned(_) => (
+            accounts.len().checked_sub(3)?,
+            accounts.len().checked_sub(2)?,
+        ),
+        _ => return None,
+    };
+    Some((*accounts.get(market)?, *accounts.get(event_queue)?))
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct QueueEvent {
+    pub market: Pubkey,
+    pub event_queue: Pubkey,
+    pub seq_num: u64,
+    pub event: Event,
+}
+
+lazy_static! {
+    /// Events each event queue gained and lost in the slot being indexed. They are derived once
+    /// per queue and slot, then handed out to the instructions of the slot in order.
+    static ref SLOT_EVENTS: Mutex<HashMap<Pubkey, SlotEvents>> = Mutex::new(HashMap::new());
+}
+
+/// Events of one event queue in one slot, with the pushed events instructions claimed and
+/// the first popped event no crank claimed yet.
+#[derive(Default)]
+struct SlotEvents {
+    slot: u64,
+    pushed: Vec<(u64, Event)>,
+    claimed: Vec<bool>,
+    consumed: Vec<(u64, Event)>,
+    next_consumed: usize,
+    /// Requests MatchOrders popped from the request queue of the market in the slot, read at
+    /// the first MatchOrders.
+    requests: Option<Vec<Request>>,
+    next_request: usize,
+}
+
+/// Coin quantity of a fill, bids receive coin and asks pay it.
+fn coin_qty(event: &Event) -> u64 {
+    match event.side() {
+        Side::Bid => event.native_qty_released,
+        Side::Ask => event.native_qty_paid,
+    }
+}
+
+impl SlotEvents {
+    fn unclaimed(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.pushed.len()).filter(move |index| !self.claimed[*index])
+    }
+    /// Indexes of the events of the taker order whose first unclaimed Fill or Out matches
+    /// `is_taker`: the maker events right before its Fill, the Fill and the Out right after
+    /// it. A cancel is a taker Out alone.
+    fn taker_events<F: Fn(&Event) -> bool>(&self, is_taker: F) -> Vec<usize> {
+        let first = match self.unclaimed().find(|index| {
+            let event = &self.pushed[*index].1;
+            (event.is_fill() || event.is_out()) && !event.is_maker() && is_taker(event)
+        }) {
+            Some(first) => first,
+            None => return Vec::new(),
+        };
+        let mut indexes = self.maker_events(first);
+        indexes.push(first);
+        if self.pushed[first].1.is_fill() {
+            let next = first + 1;
+            if let Some((_, event)) = self.pushed.get(next) {
+                if !self.claimed[next] && event.is_out() && is_taker(event) {
+                    indexes.push(next);
+                }
+            }
+        }
+        indexes
+    }
+    /// Maker Fill and Out events matched by the taker Fill at `taker`: the unclaimed events
+    /// right before it whose coin quantities add up to the taker's. None when they do not, so
+    /// that the events of other instructions are never taken.
+    fn maker_events(&self, taker: usize) -> Vec<usize> {
+        let event = &self.pushed[taker].1;
+        if !event.is_fill() {
+            return Vec::new();
+        }
+        let mut remaining = coin_qty(event);
+        let mut indexes = Vec::new();
+        for index in (0..taker).rev() {
+            if remaining == 0 {
+                break;
+            }
+            let maker = &self.pushed[index].1;
+            if self.claimed[index] || maker.side() == event.side() {
+                return Vec::new();
+            }
+            if maker.is_fill() && maker.is_maker() {
+                remaining = match remaining.checked_sub(coin_qty(maker)) {
+                    Some(remaining) => remaining,
+                    None => return Vec::new(),
+                };
+            } else if !maker.is_out() {
+                return Vec::new();
+            }
+            indexes.push(index);
+        }
+        if remaining != 0 {
+            return Vec::new();
+        }
+        indexes.reverse();
+        indexes
+    }
+    /// Take the events `instruction` pushed to or popped from the queue. An instruction
+    /// takes the events of its own orders: the taker events of a new order, the Out of a
+    /// cancelled order, the Outs of a pruned open orders account and, for MatchOrders, the
+    /// taker events of the requests it popped. Events no instruction claims are left to the
+    /// crank, which pops the events of the open orders accounts it was passed.
+    fn claim(&mut self, instruction: &MarketInstruction, accounts: &[Pubkey]) -> Vec<(u64, Event)> {
+        let mut indexes = match instruction {
+            MarketInstruction::NewOrderV3(arg) => match accounts.get(1) {
+                Some(open_orders) => self.taker_events(|event| {
+                    event.owner == *open_orders && event.client_order_id == arg.client_order_id
+                }),
+                None => Vec::new(),
+            },
+            MarketInstruction::CancelOrderV2(arg) => match accounts.get(3) {
+                Some(open_orders) => self.taker_events(|event| {
+                    event.is_out() && event.owner == *open_orders && event.order_id == arg.order_id
+                }),
+                None => Vec::new(),
+            },
+            MarketInstruction::CancelOrderByClientIdV2(client_order_id) => match accounts.get(3) {
+                Some(open_orders) => self.taker_events(|event| {
+                    event.is_out()
+                        && event.owner == *open_orders
+                        && event.client_order_id == *client_order_id
+                }),
+                None => Vec::new(),
+            },
+            MarketInstruction::Prune(_) => match accounts.get(4) {
+                Some(open_orders) => {
+                    let is_pruned = |index: &usize| {
+                        let event = &self.pushed[*index].1;
+                        !self.claimed[*index] && event.is_out() && event.owner == *open_orders
+                    };
+                    match self.unclaimed().find(is_pruned) {
+                        Some(first) => (first..self.pushed.len()).take_while(is_pruned).collect(),
+                        None => Vec::new(),
+                    }
+                }
+                None => Vec::new(),
+            },
+            MarketInstruction::MatchOrders(limit) => {
+                let requests: Vec<Request> = self
+                    .requests
+                    .iter()
+                    .flatten()
+                    .skip(self.next_request)
+                    .take(*limit as usize)
+                    .cloned()
+                    .collect();
+                self.next_request += requests.len();
+                let mut indexes = Vec::new();
+                for request in requests {
+                    let taker = self.taker_events(|event| {
+                        event.owner == request.owner && event.order_id == request.order_id
+                    });
+                    for index in &taker {
+                        self.claimed[*index] = true;
+                    }
+                    indexes.extend(taker);
+                }
+                indexes
+            }
+            MarketInstruction::ConsumeEvents(limit)
+            | MarketInstruction::ConsumeEventsPermissioned(limit) => {
+                let open_orders = open_orders_accounts(instruction, accounts);
+                let popped: Vec<(u64, Event)> = self.consumed[self.next_consumed..]
+                    .iter()
+                    .take(*limit as usize)
+                    .take_while(|(_, event)| open_orders.contains(&event.owner))
+                    .cloned()
+                    .collect();
+                self.next_consumed += popped.len();
+                return popped;
+            }
+            _ => Vec::new(),
+        };
+        indexes.sort_unstable();
+        indexes
+            .into_iter()
+            .map(|index| {
+                self.claimed[index] = true;
+                self.pushed[index].clone()
+            })
+            .collect()
+    }
+}
+
+/// Forget the events derived for the current slot of each queue, e.g. when its block is
+/// indexed again.
+pub fn forget_slot_events() {
+    SLOT_EVENTS.lock().unwrap().clear();
+}
+
+/// Derive the Fill and Out events an instruction pushed to (or, for the crank, popped from)
+/// the event queue by comparing event queue snapshots around the instruction slot.
+pub fn process_instruction(
+    ctx: &InstructionContext,
+    account_infos: &AccountInfos,
+    accounts: &[Pubkey],
+    instruction: &MarketInstruction,
+) -> Result<Vec<QueueEvent>, anyhow::Error> {
+    let (market, event_queue) = match event_queue_accounts(instruction, accounts) {
+        Some(keys) => keys,
+        None => return Ok(Vec::new()),
+    };
+    let mut slot_events = SLOT_EVENTS.lock().unwrap();
+    if slot_events
+        .get(&event_queue)
+        .map(|queue_events| queue_events.slot)
+        != Some(ctx.slot)
+    {
+        let mut queue_events = SlotEvents {
+            slot: ctx.slot,
+            ..SlotEvents::default()
+        };
+        if let Some((before, after)) = account_infos.event_queue_around(&event_queue, ctx.slot)? {
+            queue_events.pushed = clone_events(after.pushed_since(&before.header));
+            queue_events.claimed = vec![false; queue_events.pushed.len()];
+            queue_events.consumed = clone_events(before.consumed_by(&after.header));
+        }
+        slot_events.insert(event_queue, queue_events);
+    }
+    let queue_events = slot_events.get_mut(&event_queue).unwrap();
+    if let (MarketInstruction::MatchOrders(_), None) = (instruction, &queue_events.requests) {
+        let requests = match accounts.get(1) {
+            Some(request_queue) => account_infos.request_queue_around(request_queue, ctx.slot)?,
+            None => None,
+        };
+        queue_events.requests = Some(
+            requests
+                .map(|(before, after)| {
+                    after
+                        .popped_since(&before.header)
+                        .into_iter()
+                        .cloned()
+                        .collect()
+                })
+                .unwrap_or_default(),
+        );
+    }
+    let mut claimed = queue_events.claim(instruction, accounts);
+    if matches!(
+        instruction,
+        MarketInstruction::ConsumeEvents(_) | MarketInstruction::ConsumeEventsPermissioned(_)
+    ) {
+        // Events already saved with the instruction that pushed them keep that row
+        let orders = order::ORDERS.lock().unwrap();
+        claimed.retain(|(seq_num, _)| !orders.is_applied(&event_queue, *seq_num));
+    }
+    Ok(claimed
+        .into_iter()
+        .map(|(seq_num, event)| {
+            save_event(ctx, &market, &event_queue, seq_num, &event);
+            QueueEvent {
+                market,
+                event_queue,
+                seq_num,
+                event,
+            }
+        })
+        .collect())
+}
+
+fn clone_events(events: Vec<(u64, &Event)>) -> Vec<(u64, Event)> {
+    events
+        .into_iter()
+        .map(|(seq_num, event)| (seq_num, event.clone()))
+        .collect()
+}
+
+fn save_event(
+    ctx: &InstructionContext,
+    market: &Pubkey,
+    event_queue: &Pubkey,
+    seq_num: u64,
+    event: &Event,
+) {
+    if let Some((entity_name, map)) = event_attributes(ctx, market, event_queue, seq_num, event) {
+        Entity::from(map).save(entity_name);
+    }
+}
+
+/// `Fill` or `Out` row of an event, `None` for events of neither kind.
+pub fn event_attributes(
+    ctx: &InstructionContext,
+    market: &Pubkey,
+    event_queue: &Pubkey,
+    seq_num: u64,
+    event: &Event,
+) -> Option<(&'static str, HashMap<Attribute, Value>)> {
+    let mut map: HashMap<Attribute, Value> = HashMap::default();
+    // Sequence numbers are unique per queue, so crank and matching instructions of the same
+    // event write the same row.
+    map.insert(
+        "id".to_string(),
+        Value::from(format!("{}-{}", event_queue, seq_num)),
+    );
+    ctx.insert_header(&mut map);
+    map.insert("market".to_string(), Value::from(market.to_string()));
+    map.insert(
+        "event_queue".to_string(),
+        Value::from(event_queue.to_string()),
+    );
+    map.insert("seq_num".to_string(), Value::from(seq_num));
+    map.insert(
+        "side".to_string(),
+        Value::from(serde_json::to_string(&event.side()).unwrap_or_default()),
+    );
+    map.insert(
+        "open_orders".to_string(),
+        Value::from(event.owner.to_string()),
+    );
+    map.insert("owner_slot".to_string(), Value::from(event.owner_slot));
+    map.insert("order_id".to_string(), order_id::to_value(event.order_id));
+    map.insert(
+        "price_lots".to_string(),
+        Value::from(order_id::price_lots(event.order_id)),
+    );
+    // `seq_num` is the event queue position, the order keeps its own sequence number
+    map.insert(
+        "order_seq_num".to_string(),
+        Value::from(order_id::seq_num(event.order_id, &event.side())),
+    );
+    map.insert(
+        "client_order_id".to_string(),
+        Value::from(event.client_order_id),
+    );
+    if event.is_fill() {
+        map.insert("maker".to_string(), Value::from(event.is_maker()));
+        map.insert("fee_tier".to_string(), Value::from(event.fee_tier));
+        map.insert(
+            "native_qty_paid".to_string(),
+            Value::from(event.native_qty_paid),
+        );
+        map.insert(
+            "native_qty_released".to_string(),
+            Value::from(event.native_qty_released),
+        );
+        map.insert(
+            "native_fee_or_rebate".to_string(),
+            Value::from(event.native_fee_or_rebate),
+        );
+        Some(("Fill", map))
+    } else if event.is_out() {
+        map.insert(
+            "release_funds".to_string(),
+            Value::from(event.release_funds()),
+        );
+        map.insert(
+            "native_qty_unlocked".to_string(),
+            Value::from(event.native_qty_released),
+        );
+        map.insert(
+            "native_qty_still_locked".to_string(),
+            Value::from(event.native_qty_paid),
+        );
+        Some(("Out", map))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::account_source::{AccountInfos, MemorySource};
+    use crate::batch;
+    use crate::derived;
+    use crate::generated::instruction::*;
+    use crate::state::event_queue::{event_flag, Event};
+    use crate::testing::{ctx, event_queue_data, key, lock_state, stored};
+    use massbit_solana_sdk::entity::Value;
+    use solana_program::message::Message;
+    use solana_program::pubkey::Pubkey;
+    use std::num::NonZeroU64;
+    use std::sync::Arc;
+
+    fn fill(owner: Pubkey, maker: bool, bid: bool, order_id: u128, client_order_id: u64) -> Event {
+        let mut event_flags = event_flag::FILL;
+        if maker {
+            event_flags |= event_flag::MAKER;
+        }
+        // One coin lot of 10 at a price of 500, bids receive coin and asks pay it
+        let (native_qty_released, native_qty_paid) = match bid {
+            true => (10, 500),
+            false => (500, 10),
+        };
+        if bid {
+            event_flags |= event_flag::BID;
+        }
+        Event {
+            event_flags,
+            owner_slot: 0,
+            fee_tier: 0,
+            native_qty_released,
+            native_qty_paid,
+            native_fee_or_rebate: 1,
+            order_id,
+            owner,
+            client_order_id,
+        }
+    }
+
+    fn new_order(client_order_id: u64) -> MarketInstruction {
+        MarketInstruction::NewOrderV3(NewOrderInstructionV3 {
+            side: Side::Bid,
+            limit_price: NonZeroU64::new(50).unwrap(),
+            max_coin_qty: NonZeroU64::new(2).unwrap(),
+            max_native_pc_qty_including_fees: NonZeroU64::new(1_000).unwrap(),
+            self_trade_behavior: SelfTradeBehavior::DecrementTake,
+            order_type: OrderType::Limit,
+            client_order_id,
+            limit: 10,
+        })
+    }
+
+    #[test]
+    fn attributes_slot_events_to_their_instruction() {
+        let _state = lock_state();
+        let (market, event_queue) = (key(60), key(61));
+        let (taker_a, taker_b, maker_a, maker_b, stray) =
+            (key(62), key(63), key(64), key(65), key(67));
+        let out = |owner: Pubkey, bid: bool, order_id: u128, client_order_id: u64| Event {
+            event_flags: event_flag::OUT | event_flag::RELEASE_FUNDS,
+            native_qty_released: 0,
+            native_qty_paid: 0,
+            ..fill(owner, false, bid, order_id, client_order_id)
+        };
+        let events = [
+            // Pushed by an instruction of another program
+            fill(stray, true, false, 50 << 64 | 5, 0),
+            fill(maker_a, true, false, 50 << 64 | 1, 0),
+            fill(taker_a, false, true, 50 << 64 | !3u64 as u128, 1),
+            out(taker_a, true, 50 << 64 | !3u64 as u128, 1),
+            fill(maker_b, true, false, 50 << 64 | 2, 0),
+            out(maker_b, false, 50 << 64 | 2, 0),
+            fill(taker_b, false, true, 50 << 64 | !4u64 as u128, 2),
+        ];
+        let source = MemorySource::default();
+        source.insert(event_queue, 199, event_queue_data(40, &[]));
+        source.insert(event_queue, 200, event_queue_data(47, &events));
+        let account_infos = AccountInfos::new(Some(Arc::new(source)));
+
+        let instructions = [
+            (taker_a, new_order(1)),
+            (taker_b, new_order(2)),
+            (key(66), MarketInstruction::MatchOrders(5)),
+        ];
+        for (instruction_index, (open_orders, instruction)) in instructions.iter().enumerate() {
+            let accounts = match instruction {
+                MarketInstruction::MatchOrders(_) => vec![market, key(68), event_queue],
+                _ => vec![market, *open_orders, key(66), event_queue],
+            };
+            derived::process_instruction(
+                &ctx(200, instruction_index),
+                &account_infos,
+                &Message::default(),
+                &accounts,
+                instruction,
+            )
+            .unwrap();
+        }
+        batch::commit(&"hash-200".to_string(), 200).unwrap();
+
+        let fills = stored("Fill");
+        let outs = stored("Out");
+        let instruction_index = |seq_num: u64| {
+            let id = format!("{}-{}", event_queue, seq_num);
+            fills
+                .get(&id)
+                .or_else(|| outs.get(&id))
+                .and_then(|row| row.get("instruction_index").cloned())
+        };
+        // The stray maker fill is left to the crank, MatchOrders popped no request
+        assert_eq!(instruction_index(40), None);
+        for (seq_num, expected) in [(41, 0u64), (42, 0), (43, 0), (44, 1), (45, 1), (46, 1)] {
+            assert_eq!(instruction_index(seq_num), Some(Value::from(expected)));
+        }
+        assert_eq!(outs.len(), 2);
+        // Each taker order got its own fill and no order was opened for the other taker
+        let orders = stored("Order");
+        for open_orders in [taker_a, taker_b] {
+            let placed: Vec<_> = orders
+                .values()
+                .filter(|order| {
+                    order.get("open_orders") == Some(&Value::from(open_orders.to_string()))
+                })
+                .collect();
+            assert_eq!(placed.len(), 1);
+            assert_eq!(placed[0].get("fill_count"), Some(&Value::from(1u64)));
+        }
+    }
+}
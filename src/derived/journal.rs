@@ -0,0 +1,45 @@
+use std::collections::VecDeque;
+
+/// Slots of history kept to undo derived state on a reorg. Forks resolve well within this
+/// many slots, older changes are treated as final.
+pub const ROLLBACK_DEPTH: u64 = 512;
+
+/// Prior values of the keys of an in-memory state, by the slot that changed them.
+pub struct Journal<K, V> {
+    entries: VecDeque<(u64, K, Option<V>)>,
+}
+
+impl<K, V> Default for Journal<K, V> {
+    fn default() -> Self {
+        Journal {
+            entries: VecDeque::new(),
+        }
+    }
+}
+
+impl<K, V> Journal<K, V> {
+    /// Remember `prior`, the value of `key` before it was changed at `slot`, `None` when the
+    /// key did not exist.
+    pub fn record(&mut self, slot: u64, key: K, prior: Option<V>) {
+        while let Some((oldest, _, _)) = self.entries.front() {
+            if oldest + ROLLBACK_DEPTH >= slot {
+                break;
+            }
+            self.entries.pop_front();
+        }
+        self.entries.push_back((slot, key, prior));
+    }
+    /// Take the changes made above `slot`, latest first. Restoring the prior values in this
+    /// order brings each key back to its value at `slot`.
+    pub fn rollback(&mut self, slot: u64) -> Vec<(K, Option<V>)> {
+        let mut undo = Vec::new();
+        while let Some((changed, _, _)) = self.entries.back() {
+            if *changed <= slot {
+                break;
+            }
+            let (_, key, prior) = self.entries.pop_back().unwrap();
+            undo.push((key, prior));
+        }
+        undo
+    }
+}
@@ -0,0 +1,409 @@
+use crate::account_source::AccountInfos;
+use crate::context::InstructionContext;
+use crate::derived::journal::Journal;
+use crate::generated::handler::EntityExt;
+use crate::generated::instruction::MarketInstruction;
+use crate::state::market::MarketState;
+use crate::token;
+use lazy_static::lazy_static;
+use massbit_solana_sdk::entity::{Attribute, Entity, Value};
+use massbit_solana_sdk::scalar::{BigDecimal, BigInt};
+use solana_program::pubkey::Pubkey;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+lazy_static! {
+    pub static ref MARKETS: Mutex<MarketRegistry> = Mutex::new(MarketRegistry::default());
+}
+
+/// Accounts and parameters of a market, kept in memory to read its lot denominated amounts
+/// and to rewrite the `Market` row when the market is disabled or swept.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MarketInfo {
+    pub market: Pubkey,
+    pub request_queue: Pubkey,
+    pub event_queue: Pubkey,
+    pub bids: Pubkey,
+    pub asks: Pubkey,
+    pub coin_vault: Pubkey,
+    pub pc_vault: Pubkey,
+    pub coin_mint: Pubkey,
+    pub pc_mint: Pubkey,
+    pub coin_lot_size: u64,
+    pub pc_lot_size: u64,
+    pub fee_rate_bps: u16,
+    pub vault_signer_nonce: u64,
+    pub pc_dust_threshold: u64,
+    /// Unknown for markets created before the indexed block range.
+    pub created_slot: Option<u64>,
+    pub created_signature: Option<String>,
+    pub disabled: bool,
+    pub disabled_slot: Option<u64>,
+    pub last_sweep_slot: Option<u64>,
+    pub last_sweep_signature: Option<String>,
+    pub updated_slot: u64,
+    pub updated_block_hash: String,
+}
+impl MarketInfo {
+    /// Market read from its account at `ctx.slot`.
+    pub fn from_state(ctx: &InstructionContext, market: &Pubkey, state: &MarketState) -> Self {
+        MarketInfo {
+            market: *market,
+            request_queue: state.request_queue,
+            event_queue: state.event_queue,
+            bids: state.bids,
+            asks: state.asks,
+            coin_vault: state.coin_vault,
+            pc_vault: state.pc_vault,
+            coin_mint: state.coin_mint,
+            pc_mint: state.pc_mint,
+            coin_lot_size: state.coin_lot_size,
+            pc_lot_size: state.pc_lot_size,
+            fee_rate_bps: state.fee_rate_bps as u16,
+            vault_signer_nonce: state.vault_signer_nonce,
+            pc_dust_threshold: state.pc_dust_threshold,
+            created_slot: None,
+            created_signature: None,
+            disabled: state.is_disabled(),
+            disabled_slot: None,
+            last_sweep_slot: None,
+            last_sweep_signature: None,
+            updated_slot: ctx.slot,
+            updated_block_hash: ctx.block_hash.clone(),
+        }
+    }
+    pub fn coin_decimals(&self) -> Option<u8> {
+        token::decimals(&self.coin_mint)
+    }
+    pub fn pc_decimals(&self) -> Option<u8> {
+        token::decimals(&self.pc_mint)
+    }
+    /// `COIN/PC` when both mints are in the bundled token list.
+    pub fn symbol(&self) -> Option<String> {
+        Some(format!(
+            "{}/{}",
+            token::symbol(&self.coin_mint)?,
+            token::symbol(&self.pc_mint)?
+        ))
+    }
+    /// Price of one coin in quote currency:
+    /// `price_lots * pc_lot_size * 10^coin_decimals / (coin_lot_size * 10^pc_decimals)`.
+    pub fn ui_price(&self, price_lots: u64) -> Option<BigDecimal> {
+        if self.coin_lot_size == 0 {
+            return None;
+        }
+        let exp = self.coin_decimals()? as i64 - self.pc_decimals()? as i64;
+        let native = price_lots as u128 * self.pc_lot_size as u128;
+        Some(
+            BigDecimal::new(BigInt::from_unsigned_bytes_le(&native.to_le_bytes()), exp)
+                / BigDecimal::new(BigInt::from(self.coin_lot_size), 0),
+        )
+    }
+    /// Quantity in coin: `qty_lots * coin_lot_size / 10^coin_decimals`.
+    pub fn ui_size(&self, qty_lots: u64) -> Option<BigDecimal> {
+        let native = qty_lots as u128 * self.coin_lot_size as u128;
+        Some(BigDecimal::new(
+            BigInt::from_unsigned_bytes_le(&native.to_le_bytes()),
+            -(self.coin_decimals()? as i64),
+        ))
+    }
+}
+
+/// Markets seen in InitializeMarket instructions. Markets created before the indexed block
+/// range are read from their account the first time one of their instructions is seen; without
+/// an account source they stay unknown and their orders keep only the raw lot columns.
+#[derive(Default)]
+pub struct MarketRegistry {
+    markets: HashMap<Pubkey, MarketInfo>,
+    journal: Journal<Pubkey, MarketInfo>,
+}
+impl MarketRegistry {
+    pub fn get(&self, market: &Pubkey) -> Option<&MarketInfo> {
+        self.markets.get(market)
+    }
+    pub fn register(&mut self, info: MarketInfo) {
+        let prior = self.markets.get(&info.market).cloned();
+        self.journal.record(info.updated_slot, info.market, prior);
+        self.markets.insert(info.market, info);
+    }
+    pub fn update<F: FnOnce(&mut MarketInfo)>(
+        &mut self,
+        ctx: &InstructionContext,
+        market: &Pubkey,
+        apply: F,
+    ) -> Option<&MarketInfo> {
+        let info = self.markets.get_mut(market)?;
+        self.journal.record(ctx.slot, *market, Some(info.clone()));
+        apply(info);
+        info.updated_slot = ctx.slot;
+        info.updated_block_hash = ctx.block_hash.clone();
+        Some(info)
+    }
+    /// Undo the changes made above `slot`. Returns the touched markets with their state at
+    /// `slot`, `None` for markets initialized above it.
+    pub fn rollback(&mut self, slot: u64) -> Vec<(Pubkey, Option<MarketInfo>)> {
+        let mut touched: Vec<Pubkey> = Vec::new();
+        for (market, prior) in self.journal.rollback(slot) {
+            match prior {
+                Some(info) => self.markets.insert(market, info),
+                None => self.markets.remove(&market),
+            };
+            if !touched.contains(&market) {
+                touched.push(market);
+            }
+        }
+        touched
+            .into_iter()
+            .map(|market| (market, self.markets.get(&market).cloned()))
+            .collect()
+    }
+}
+
+pub fn market_info(market: &Pubkey) -> Option<MarketInfo> {
+    MARKETS.lock().unwrap().get(market).cloned()
+}
+
+/// Insert the UI `price` and `size` of an order, `null` when the market or one of its mints
+/// is unknown.
+pub fn insert_price_size(
+    map: &mut HashMap<Attribute, Value>,
+    market: Option<&Pubkey>,
+    price_lots: u64,
+    qty_lots: Option<u64>,
+) {
+    let info = market.and_then(market_info);
+    map.insert(
+        "price".to_string(),
+        info.as_ref()
+            .and_then(|info| info.ui_price(price_lots))
+            .map(Value::from)
+            .unwrap_or(Value::Null),
+    );
+    map.insert(
+        "size".to_string(),
+        info.as_ref()
+            .zip(qty_lots)
+            .and_then(|(info, qty_lots)| info.ui_size(qty_lots))
+            .map(Value::from)
+            .unwrap_or(Value::Null),
+    );
+}
+
+/// Register a market created before the indexed block range from its account at `ctx.slot`.
+fn seed(
+    registry: &mut MarketRegistry,
+    ctx: &InstructionContext,
+    account_infos: &AccountInfos,
+    market: &Pubkey,
+) -> Result<(), anyhow::Error> {
+    if registry.get(market).is_some() || !account_infos.is_available() {
+        return Ok(());
+    }
+    if let Some(state) = account_infos.market_state(market, ctx.slot)? {
+        log::debug!(
+            "{} Read market created before the indexed range",
+            ctx.log_fields("Market", Some(market))
+        );
+        let info = MarketInfo::from_state(ctx, market, &state);
+        save_market(&info);
+        registry.register(info);
+    }
+    Ok(())
+}
+
+pub fn process_instruction(
+    ctx: &InstructionContext,
+    account_infos: &AccountInfos,
+    accounts: &[Pubkey],
+    instruction: &MarketInstruction,
+) -> Result<(), anyhow::Error> {
+    let mut registry = MARKETS.lock().unwrap();
+    if !matches!(instruction, MarketInstruction::InitializeMarket(_)) {
+        if let Some(market) = instruction
+            .market_account()
+            .and_then(|index| accounts.get(index))
+        {
+            seed(&mut registry, ctx, account_infos, market)?;
+        }
+    }
+    let market = match accounts.first() {
+        Some(market) => market,
+        None => return Ok(()),
+    };
+    match instruction {
+        MarketInstruction::InitializeMarket(arg) => {
+            let account = |index: usize| accounts.get(index).cloned().unwrap_or_default();
+            let info = MarketInfo {
+                market: *market,
+                request_queue: account(1),
+                event_queue: account(2),
+                bids: account(3),
+                asks: account(4),
+                coin_vault: account(5),
+                pc_vault: account(6),
+                coin_mint: account(7),
+                pc_mint: account(8),
+                coin_lot_size: arg.coin_lot_size,
+                pc_lot_size: arg.pc_lot_size,
+                fee_rate_bps: arg.fee_rate_bps,
+                vault_signer_nonce: arg.vault_signer_nonce,
+                pc_dust_threshold: arg.pc_dust_threshold,
+                created_slot: Some(ctx.slot),
+                created_signature: Some(ctx.signature.clone()),
+                disabled: false,
+                disabled_slot: None,
+                last_sweep_slot: None,
+                last_sweep_signature: None,
+                updated_slot: ctx.slot,
+                updated_block_hash: ctx.block_hash.clone(),
+            };
+            save_market(&info);
+            registry.register(info);
+        }
+        MarketInstruction::DisableMarket => match registry.update(ctx, market, |info| {
+            info.disabled = true;
+            info.disabled_slot = Some(ctx.slot);
+        }) {
+            Some(info) => save_market(info),
+            None => log::debug!("Disabled market {} is unknown", market),
+        },
+        MarketInstruction::SweepFees => match registry.update(ctx, market, |info| {
+            info.last_sweep_slot = Some(ctx.slot);
+            info.last_sweep_signature = Some(ctx.signature.clone());
+        }) {
+            Some(info) => save_market(info),
+            None => log::debug!("Swept market {} is unknown", market),
+        },
+        _ => {}
+    }
+    Ok(())
+}
+
+pub fn save_market(info: &MarketInfo) {
+    Entity::from(market_attributes(info)).save("Market");
+}
+
+pub fn market_attributes(info: &MarketInfo) -> HashMap<Attribute, Value> {
+    let mut map: HashMap<Attribute, Value> = HashMap::default();
+    map.insert("id".to_string(), Value::from(info.market.to_string()));
+    for (name, pubkey) in [
+        ("request_queue", &info.request_queue),
+        ("event_queue", &info.event_queue),
+        ("bids", &info.bids),
+        ("asks", &info.asks),
+        ("coin_vault", &info.coin_vault),
+        ("pc_vault", &info.pc_vault),
+        ("coin_mint", &info.coin_mint),
+        ("pc_mint", &info.pc_mint),
+    ] {
+        map.insert(name.to_string(), Value::from(pubkey.to_string()));
+    }
+    map.insert(
+        "symbol".to_string(),
+        info.symbol().map(Value::from).unwrap_or(Value::Null),
+    );
+    map.insert(
+        "coin_symbol".to_string(),
+        token::symbol(&info.coin_mint)
+            .map(Value::from)
+            .unwrap_or(Value::Null),
+    );
+    map.insert(
+        "pc_symbol".to_string(),
+        token::symbol(&info.pc_mint)
+            .map(Value::from)
+            .unwrap_or(Value::Null),
+    );
+    map.insert(
+        "coin_decimals".to_string(),
+        info.coin_decimals().map(Value::from).unwrap_or(Value::Null),
+    );
+    map.insert(
+        "pc_decimals".to_string(),
+        info.pc_decimals().map(Value::from).unwrap_or(Value::Null),
+    );
+    map.insert("coin_lot_size".to_string(), Value::from(info.coin_lot_size));
+    map.insert("pc_lot_size".to_string(), Value::from(info.pc_lot_size));
+    map.insert("fee_rate_bps".to_string(), Value::from(info.fee_rate_bps));
+    map.insert(
+        "vault_signer_nonce".to_string(),
+        Value::from(info.vault_signer_nonce),
+    );
+    map.insert(
+        "pc_dust_threshold".to_string(),
+        Value::from(info.pc_dust_threshold),
+    );
+    map.insert(
+        "created_slot".to_string(),
+        info.created_slot.map(Value::from).unwrap_or(Value::Null),
+    );
+    map.insert(
+        "created_signature".to_string(),
+        info.created_signature
+            .clone()
+            .map(Value::from)
+            .unwrap_or(Value::Null),
+    );
+    map.insert("disabled".to_string(), Value::from(info.disabled));
+    map.insert(
+        "disabled_slot".to_string(),
+        info.disabled_slot.map(Value::from).unwrap_or(Value::Null),
+    );
+    map.insert(
+        "last_sweep_slot".to_string(),
+        info.last_sweep_slot.map(Value::from).unwrap_or(Value::Null),
+    );
+    map.insert(
+        "last_sweep_signature".to_string(),
+        info.last_sweep_signature
+            .clone()
+            .map(Value::from)
+            .unwrap_or(Value::Null),
+    );
+    map.insert("updated_slot".to_string(), Value::from(info.updated_slot));
+    map.insert(
+        "updated_block_hash".to_string(),
+        Value::from(info.updated_block_hash.clone()),
+    );
+    map
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::account_source::MemorySource;
+    use crate::testing::{ctx, fixture, key, lock_state, MARKET_V3};
+    use std::sync::Arc;
+
+    #[test]
+    fn seeds_unknown_market_from_its_account() {
+        let _state = lock_state();
+        let data = fixture(MARKET_V3);
+        let state = MarketState::unpack(&data).unwrap();
+        let market = state.own_address;
+        let source = MemorySource::default();
+        source.insert(market, 90, data);
+        let account_infos = AccountInfos::new(Some(Arc::new(source)));
+
+        process_instruction(
+            &ctx(100, 0),
+            &account_infos,
+            &[market, key(30), key(31)],
+            &MarketInstruction::SweepFees,
+        )
+        .unwrap();
+        let info = market_info(&market).unwrap();
+        assert_eq!(
+            (info.bids, info.asks, info.event_queue),
+            (state.bids, state.asks, state.event_queue)
+        );
+        assert_eq!((info.coin_lot_size, info.pc_lot_size), (100_000_000, 100));
+        assert_eq!(info.pc_dust_threshold, 500);
+        assert_eq!(info.symbol().as_deref(), Some("SOL/USDC"));
+        assert_eq!((info.created_slot, info.last_sweep_slot), (None, Some(100)));
+
+        // Reverting the slot the market was first seen in forgets it again
+        MARKETS.lock().unwrap().rollback(99);
+        assert_eq!(market_info(&market), None);
+    }
+}
@@ -0,0 +1,153 @@
+//! State derived from decoded instructions on top of the per-instruction entities.
+pub mod balance;
+pub mod book;
+pub mod fill;
+pub mod journal;
+pub mod market;
+pub mod order;
+
+use crate::account_source::AccountInfos;
+use crate::batch;
+use crate::context::InstructionContext;
+use crate::generated::handler::EntityExt;
+use crate::generated::instruction::MarketInstruction;
+use anyhow::Context;
+use massbit_solana_sdk::entity::{Attribute, Entity, Value};
+use solana_program::message::Message;
+use solana_program::pubkey::Pubkey;
+use std::collections::HashMap;
+
+pub fn process_instruction(
+    ctx: &InstructionContext,
+    account_infos: &AccountInfos,
+    message: &Message,
+    accounts: &[Pubkey],
+    instruction: &MarketInstruction,
+) -> Result<(), anyhow::Error> {
+    // Markets first, so that the other derivations see markets created before the range
+    let market = market::process_instruction(ctx, account_infos, accounts, instruction);
+    let events = fill::process_instruction(ctx, account_infos, accounts, instruction);
+    // Orders still follow the instruction itself when its fills cannot be derived
+    order::process_instruction(
+        ctx,
+        account_infos,
+        accounts,
+        instruction,
+        events.as_deref().unwrap_or_default(),
+    );
+    let balances = balance::process_instruction(ctx, account_infos, message, accounts, instruction);
+    let book = book::process_instruction(ctx, account_infos, accounts, instruction);
+    market.context("Cannot read the market")?;
+    events
+        .map(|_| ())
+        .context("Cannot derive fills from the event queue")?;
+    balances.context("Cannot read open orders balances")?;
+    book.context("Cannot capture the order book")
+}
+
+/// Touched orders and markets with their state at the slot reverted to, `None` for those
+/// created above it.
+type RevertedOrders = Vec<(String, Option<order::Order>)>;
+type RevertedMarkets = Vec<(Pubkey, Option<market::MarketInfo>)>;
+
+/// Revert the in-memory derived state to `slot`: orders with the event queue positions
+/// applied, markets and the slots of the order book snapshots.
+fn revert_state(slot: u64) -> (RevertedOrders, RevertedMarkets) {
+    let orders = order::ORDERS.lock().unwrap().rollback(slot);
+    let markets = market::MARKETS.lock().unwrap().rollback(slot);
+    book::rollback(slot);
+    fill::forget_slot_events();
+    (orders, markets)
+}
+
+/// Revert the derived state to `slot`, the last committed slot, after indexing the blocks
+/// above it failed. Nothing of those blocks reached the store, so nothing is saved.
+pub fn revert(slot: u64) {
+    revert_state(slot);
+}
+
+/// Revert orders and markets to their state at `slot` after a reorganization dropped the
+/// blocks above it. Restored rows are saved again and every other row committed above `slot`
+/// gets a `Tombstone` the host deletes it by. A `Rollback` row records what was reverted.
+pub fn rollback(slot: u64, block_hash: &str) {
+    let (orders, markets) = revert_state(slot);
+    let mut restored: Vec<(String, String)> = Vec::new();
+    for (id, order) in &orders {
+        if let Some(order) = order {
+            order::save_order(order);
+            restored.push(("Order".to_string(), id.clone()));
+        }
+    }
+    for (market, info) in &markets {
+        if let Some(info) = info {
+            market::save_market(info);
+            restored.push(("Market".to_string(), market.to_string()));
+        }
+    }
+    for (entity_name, id) in batch::committed_above(slot) {
+        if !restored.contains(&(entity_name.clone(), id.clone())) {
+            Entity::from(tombstone_attributes(slot, block_hash, &entity_name, &id))
+                .save("Tombstone");
+        }
+    }
+    Entity::from(rollback_attributes(slot, block_hash, &orders, &markets)).save("Rollback");
+}
+
+/// Row `id` of `entity_name`, written above `slot` on a dropped fork.
+pub fn tombstone_attributes(
+    slot: u64,
+    block_hash: &str,
+    entity_name: &str,
+    id: &str,
+) -> HashMap<Attribute, Value> {
+    let mut map: HashMap<Attribute, Value> = HashMap::default();
+    map.insert(
+        "id".to_string(),
+        Value::from(format!("{}-{}-{}", entity_name, id, block_hash)),
+    );
+    map.insert("entity".to_string(), Value::from(entity_name.to_string()));
+    map.insert("entity_id".to_string(), Value::from(id.to_string()));
+    map.insert("slot".to_string(), Value::from(slot));
+    map.insert(
+        "block_hash".to_string(),
+        Value::from(block_hash.to_string()),
+    );
+    map
+}
+
+pub fn rollback_attributes(
+    slot: u64,
+    block_hash: &str,
+    orders: &[(String, Option<order::Order>)],
+    markets: &[(Pubkey, Option<market::MarketInfo>)],
+) -> HashMap<Attribute, Value> {
+    let reverted_orders = orders.iter().filter(|(_, order)| order.is_some()).count();
+    let reverted_markets = markets.iter().filter(|(_, info)| info.is_some()).count();
+    let mut map: HashMap<Attribute, Value> = HashMap::default();
+    map.insert(
+        "id".to_string(),
+        Value::from(format!("{}-{}", slot, block_hash)),
+    );
+    map.insert("slot".to_string(), Value::from(slot));
+    map.insert(
+        "block_hash".to_string(),
+        Value::from(block_hash.to_string()),
+    );
+    map.insert(
+        "reverted_orders".to_string(),
+        Value::from(reverted_orders as u64),
+    );
+    map.insert(
+        "dropped_orders".to_string(),
+        Value::from((orders.len() - reverted_orders) as u64),
+    );
+    map.insert(
+        "reverted_markets".to_string(),
+        Value::from(reverted_markets as u64),
+    );
+    map.insert(
+        "dropped_markets".to_string(),
+        Value::from((markets.len() - reverted_markets) as u64),
+    );
+    map
+}
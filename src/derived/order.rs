@@ -0,0 +1,1055 @@
+use crate::account_source::AccountInfos;
+use crate::context::InstructionContext;
+use crate::derived::fill::QueueEvent;
+use crate::derived::journal::Journal;
+use crate::derived::market;
+use crate::generated::handler::EntityExt;
+use crate::generated::instruction::{MarketInstruction, OrderType, Side};
+use crate::order_id;
+use crate::state::open_orders::OpenOrders;
+use lazy_static::lazy_static;
+use massbit_solana_sdk::entity::{Attribute, Entity, Value};
+use solana_program::pubkey::Pubkey;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+lazy_static! {
+    pub static ref ORDERS: Mutex<OrderTracker> = Mutex::new(OrderTracker::default());
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OrderStatus {
+    Open,
+    PartiallyFilled,
+    Filled,
+    Cancelled,
+    Pruned,
+}
+impl OrderStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OrderStatus::Open => "open",
+            OrderStatus::PartiallyFilled => "partially_filled",
+            OrderStatus::Filled => "filled",
+            OrderStatus::Cancelled => "cancelled",
+            OrderStatus::Pruned => "pruned",
+        }
+    }
+    pub fn is_terminal(&self) -> bool {
+        matches!(
+            self,
+            OrderStatus::Filled | OrderStatus::Cancelled | OrderStatus::Pruned
+        )
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Order {
+    pub id: String,
+    pub market: Pubkey,
+    pub open_orders: Pubkey,
+    pub side: Side,
+    pub order_id: Option<u128>,
+    pub client_order_id: u64,
+    pub limit_price: u64,
+    pub max_qty: Option<u64>,
+    pub order_type: Option<OrderType>,
+    pub status: OrderStatus,
+    pub native_qty_paid: u64,
+    pub native_qty_released: u64,
+    pub native_fee_or_rebate: u64,
+    pub fill_count: u64,
+    /// Coin lots filled since indexing started, unknown once a fill happened on a market whose
+    /// lot size is unknown.
+    pub filled_qty: Option<u64>,
+    /// Id and entity type of the NewOrder* row that placed the order, unknown for orders
+    /// placed before indexing started.
+    pub origin: Option<(String, &'static str)>,
+    pub origin_signature: Option<String>,
+    pub created_slot: u64,
+    pub updated_slot: u64,
+    pub updated_block_hash: String,
+    pub updated_signature: String,
+}
+
+/// Orders that are still live on the book. Terminal orders are saved once more and dropped.
+#[derive(Default)]
+pub struct OrderTracker {
+    orders: HashMap<String, Order>,
+    by_order_id: HashMap<(Pubkey, u128), String>,
+    by_client_id: HashMap<(Pubkey, u64), String>,
+    by_open_orders: HashMap<Pubkey, Vec<String>>,
+    next_seq_num: HashMap<Pubkey, u64>,
+    journal: Journal<String, Order>,
+    seq_num_journal: Journal<Pubkey, u64>,
+}
+
+impl Order {
+    /// Whether the filled quantity reached the order quantity.
+    pub fn is_filled(&self) -> bool {
+        match (self.filled_qty, self.max_qty) {
+            (Some(filled_qty), Some(max_qty)) => filled_qty >= max_qty,
+            _ => false,
+        }
+    }
+    /// Whether the order is one of the resting orders of its open orders account.
+    fn is_resting(&self, state: &OpenOrders) -> bool {
+        state
+            .iter_orders()
+            .any(|(_, side, order_id, client_order_id)| match self.order_id {
+                Some(known) => known == order_id,
+                None => {
+                    side == self.side
+                        && order_id::price_lots(order_id) == self.limit_price
+                        && (self.client_order_id == 0 || client_order_id == self.client_order_id)
+                }
+            })
+    }
+}
+
+/// `<market>-<order_id>`, the id of the row of an order whose order id is known when it is
+/// first seen, placed or filled. Client order ids are not unique, clients reuse them or leave
+/// them at 0.
+pub fn order_entity_id(market: &Pubkey, order_id: u128) -> String {
+    format!("{}-{}", market, order_id)
+}
+
+impl OrderTracker {
+    /// Open an order placed by an instruction. The order id comes from the taker Fill the
+    /// instruction pushed or, for an order resting on the book, from its open orders account
+    /// at the end of the slot. Orders whose order id cannot be told keep the instruction id.
+    pub fn place(
+        &mut self,
+        mut order: Order,
+        events: &[QueueEvent],
+        open_orders: Option<&OpenOrders>,
+    ) -> Order {
+        order.order_id = events
+            .iter()
+            .map(|queue_event| &queue_event.event)
+            .find(|event| {
+                event.is_fill()
+                    && !event.is_maker()
+                    && event.owner == order.open_orders
+                    && event.client_order_id == order.client_order_id
+            })
+            .map(|event| event.order_id)
+            .or_else(|| open_orders.and_then(|state| self.resting_order_id(&order, state)));
+        if let Some(order_id) = order.order_id {
+            order.id = order_entity_id(&order.market, order_id);
+        }
+        self.open(order)
+    }
+    /// Latest resting order of `state` matching `order` that no tracked order claims.
+    fn resting_order_id(&self, order: &Order, state: &OpenOrders) -> Option<u128> {
+        state
+            .iter_orders()
+            .filter(|(_, side, order_id, client_order_id)| {
+                *side == order.side
+                    && order_id::price_lots(*order_id) == order.limit_price
+                    && (order.client_order_id == 0 || *client_order_id == order.client_order_id)
+                    && !self.by_order_id.contains_key(&(order.market, *order_id))
+            })
+            .max_by_key(|(_, side, order_id, _)| order_id::seq_num(*order_id, side))
+            .map(|(_, _, order_id, _)| order_id)
+    }
+    /// Close an order once the instruction that placed it is done: immediate-or-cancel orders
+    /// never rest on the book, and a post-only order missing from its open orders account
+    /// without any fill was rejected for crossing the book.
+    pub fn close_placed(
+        &mut self,
+        ctx: &InstructionContext,
+        id: &str,
+        open_orders: Option<&OpenOrders>,
+    ) -> Option<Order> {
+        let order = self
+            .orders
+            .get(id)
+            .filter(|order| !order.status.is_terminal())?;
+        let rejected = order.fill_count == 0
+            && open_orders
+                .map(|state| !order.is_resting(state))
+                .unwrap_or(false);
+        match order.order_type {
+            Some(OrderType::ImmediateOrCancel) => {}
+            Some(OrderType::PostOnly) if rejected => {}
+            _ => return None,
+        }
+        self.update(ctx, id, |order| order.status = OrderStatus::Cancelled)
+    }
+    pub fn get(&self, id: &str) -> Option<&Order> {
+        self.orders.get(id)
+    }
+    pub fn open(&mut self, order: Order) -> Order {
+        let prior = self.orders.get(&order.id).cloned();
+        self.journal
+            .record(order.updated_slot, order.id.clone(), prior);
+        self.index(order)
+    }
+    fn index(&mut self, order: Order) -> Order {
+        if order.client_order_id != 0 {
+            self.by_client_id
+                .insert((order.open_orders, order.client_order_id), order.id.clone());
+        }
+        if let Some(order_id) = order.order_id {
+            self.by_order_id
+                .insert((order.market, order_id), order.id.clone());
+        }
+        self.by_open_orders
+            .entry(order.open_orders)
+            .or_default()
+            .push(order.id.clone());
+        self.orders.insert(order.id.clone(), order.clone());
+        order
+    }
+    /// Whether the event at `seq_num` of `event_queue` was applied already.
+    pub fn is_applied(&self, event_queue: &Pubkey, seq_num: u64) -> bool {
+        self.next_seq_num
+            .get(event_queue)
+            .map(|next_seq_num| seq_num < *next_seq_num)
+            .unwrap_or(false)
+    }
+    /// Apply a Fill or Out event once, events replayed by the crank are skipped.
+    /// `coin_lot_size` is the lot size of the event market, when known.
+    pub fn apply_event(
+        &mut self,
+        ctx: &InstructionContext,
+        queue_event: &QueueEvent,
+        out_status: OrderStatus,
+        coin_lot_size: Option<u64>,
+    ) -> Option<Order> {
+        let next_seq_num = self
+            .next_seq_num
+            .entry(queue_event.event_queue)
+            .or_insert(0);
+        if queue_event.seq_num < *next_seq_num {
+            return None;
+        }
+        self.seq_num_journal
+            .record(ctx.slot, queue_event.event_queue, Some(*next_seq_num));
+        *next_seq_num = queue_event.seq_num + 1;
+        let event = &queue_event.event;
+        let id = match self.find_event_order(queue_event) {
+            Some(id) => id,
+            None if event.is_fill() => {
+                self.open(Order {
+                    id: order_entity_id(&queue_event.market, event.order_id),
+                    market: queue_event.market,
+                    open_orders: event.owner,
+                    side: event.side(),
+                    order_id: Some(event.order_id),
+                    client_order_id: event.client_order_id,
+                    limit_price: order_id::price_lots(event.order_id),
+                    max_qty: None,
+                    order_type: None,
+                    status: OrderStatus::Open,
+                    native_qty_paid: 0,
+                    native_qty_released: 0,
+                    native_fee_or_rebate: 0,
+                    fill_count: 0,
+                    filled_qty: Some(0),
+                    origin: None,
+                    origin_signature: None,
+                    created_slot: ctx.slot,
+                    updated_slot: ctx.slot,
+                    updated_block_hash: ctx.block_hash.clone(),
+                    updated_signature: ctx.signature.clone(),
+                })
+                .id
+            }
+            None => return None,
+        };
+        self.by_order_id
+            .entry((queue_event.market, event.order_id))
+            .or_insert_with(|| id.clone());
+        self.update(ctx, &id, |order| {
+            order.order_id = Some(event.order_id);
+            if event.is_fill() {
+                order.native_qty_paid += event.native_qty_paid;
+                order.native_qty_released += event.native_qty_released;
+                order.native_fee_or_rebate += event.native_fee_or_rebate;
+                order.fill_count += 1;
+                // Bids receive coin, asks pay it
+                let native_coin_qty = match event.side() {
+                    Side::Bid => event.native_qty_released,
+                    Side::Ask => event.native_qty_paid,
+                };
+                order.filled_qty =
+                    order
+                        .filled_qty
+                        .zip(coin_lot_size)
+                        .map(|(filled_qty, coin_lot_size)| {
+                            filled_qty + native_coin_qty / coin_lot_size
+                        });
+                order.status = if order.is_filled() {
+                    OrderStatus::Filled
+                } else {
+                    OrderStatus::PartiallyFilled
+                };
+            } else if event.is_out() {
+                // A maker that got fully filled leaves the book releasing funds with nothing
+                // left to unlock, anything else is a cancel.
+                order.status = if order.is_filled()
+                    || (event.release_funds() && event.native_qty_released == 0)
+                {
+                    OrderStatus::Filled
+                } else {
+                    out_status
+                };
+            }
+        })
+    }
+    pub fn cancel_by_order_id(
+        &mut self,
+        ctx: &InstructionContext,
+        market: &Pubkey,
+        order_id: u128,
+    ) -> Option<Order> {
+        let id = self.by_order_id.get(&(*market, order_id))?.clone();
+        self.update(ctx, &id, |order| order.status = OrderStatus::Cancelled)
+    }
+    pub fn cancel_by_client_id(
+        &mut self,
+        ctx: &InstructionContext,
+        open_orders: &Pubkey,
+        client_order_id: u64,
+    ) -> Option<Order> {
+        let id = self
+            .by_client_id
+            .get(&(*open_orders, client_order_id))?
+            .clone();
+        self.update(ctx, &id, |order| order.status = OrderStatus::Cancelled)
+    }
+    pub fn prune(
+        &mut self,
+        ctx: &InstructionContext,
+        market: &Pubkey,
+        open_orders: &Pubkey,
+    ) -> Vec<Order> {
+        let ids: Vec<String> = self
+            .by_open_orders
+            .get(open_orders)
+            .into_iter()
+            .flatten()
+            .filter(|id| {
+                self.orders
+                    .get(*id)
+                    .map(|order| order.market == *market)
+                    .unwrap_or(false)
+            })
+            .cloned()
+            .collect();
+        ids.iter()
+            .filter_map(|id| self.update(ctx, id, |order| order.status = OrderStatus::Pruned))
+            .collect()
+    }
+    fn find_event_order(&self, queue_event: &QueueEvent) -> Option<String> {
+        let event = &queue_event.event;
+        if let Some(id) = self.by_order_id.get(&(queue_event.market, event.order_id)) {
+            return Some(id.clone());
+        }
+        if event.client_order_id != 0 {
+            return self
+                .by_client_id
+                .get(&(event.owner, event.client_order_id))
+                .cloned();
+        }
+        // Without a client id, the order id carries the limit price in its high 64 bits
+        let price = order_id::price_lots(event.order_id);
+        self.by_open_orders
+            .get(&event.owner)?
+            .iter()
+            .find(|id| {
+                self.orders
+                    .get(*id)
+                    .map(|order| {
+                        order.order_id.is_none()
+                            && order.market == queue_event.market
+                            && order.side == event.side()
+                            && order.limit_price == price
+                    })
+                    .unwrap_or(false)
+            })
+            .cloned()
+    }
+    fn update<F: FnOnce(&mut Order)>(
+        &mut self,
+        ctx: &InstructionContext,
+        id: &str,
+        apply: F,
+    ) -> Option<Order> {
+        let order = self.orders.get_mut(id)?;
+        self.journal
+            .record(ctx.slot, id.to_string(), Some(order.clone()));
+        apply(order);
+        order.updated_slot = ctx.slot;
+        order.updated_block_hash = ctx.block_hash.clone();
+        order.updated_signature = ctx.signature.clone();
+        let order = order.clone();
+        if order.status.is_terminal() {
+            self.retire(&order);
+        }
+        Some(order)
+    }
+    /// Undo the changes made above `slot`. Returns the touched order ids with their state
+    /// at `slot`, `None` for orders placed above it.
+    pub fn rollback(&mut self, slot: u64) -> Vec<(String, Option<Order>)> {
+        for (event_queue, prior) in self.seq_num_journal.rollback(slot) {
+            match prior {
+                Some(next_seq_num) => self.next_seq_num.insert(event_queue, next_seq_num),
+                None => self.next_seq_num.remove(&event_queue),
+            };
+        }
+        let mut touched: Vec<String> = Vec::new();
+        for (id, prior) in self.journal.rollback(slot) {
+            if let Some(current) = self.orders.get(&id).cloned() {
+                self.retire(&current);
+            }
+            if let Some(prior) = prior {
+                self.index(prior);
+            }
+            if !touched.contains(&id) {
+                touched.push(id);
+            }
+        }
+        touched
+            .into_iter()
+            .map(|id| {
+                let order = self.orders.get(&id).cloned();
+                (id, order)
+            })
+            .collect()
+    }
+    fn retire(&mut self, order: &Order) {
+        self.orders.remove(&order.id);
+        if let Some(order_id) = order.order_id {
+            self.by_order_id.remove(&(order.market, order_id));
+        }
+        if self
+            .by_client_id
+            .get(&(order.open_orders, order.client_order_id))
+            == Some(&order.id)
+        {
+            self.by_client_id
+                .remove(&(order.open_orders, order.client_order_id));
+        }
+        if let Some(ids) = self.by_open_orders.get_mut(&order.open_orders) {
+            ids.retain(|id| id != &order.id);
+            if ids.is_empty() {
+                self.by_open_orders.remove(&order.open_orders);
+            }
+        }
+    }
+}
+
+/// Order placed by a NewOrder* instruction, keyed by the instruction until its order id is
+/// known.
+fn new_order(
+    ctx: &InstructionContext,
+    accounts: &[Pubkey],
+    instruction: &MarketInstruction,
+) -> Option<Order> {
+    let (side, limit_price, max_qty, order_type, client_order_id) = match instruction {
+        MarketInstruction::NewOrder(arg) => (
+            &arg.side,
+            arg.limit_price.get(),
+            arg.max_qty.get(),
+            &arg.order_type,
+            arg.client_id,
+        ),
+        MarketInstruction::NewOrderV2(arg) => (
+            &arg.side,
+            arg.limit_price.get(),
+            arg.max_qty.get(),
+            &arg.order_type,
+            arg.client_id,
+        ),
+        MarketInstruction::NewOrderV3(arg) => (
+            &arg.side,
+            arg.limit_price.get(),
+            arg.max_coin_qty.get(),
+            &arg.order_type,
+            arg.client_order_id,
+        ),
+        _ => return None,
+    };
+    Some(Order {
+        id: ctx.entity_id(),
+        market: *accounts.first()?,
+        open_orders: *accounts.get(1)?,
+        side: side.clone(),
+        order_id: None,
+        client_order_id,
+        limit_price,
+        max_qty: Some(max_qty),
+        order_type: Some(order_type.clone()),
+        status: OrderStatus::Open,
+        native_qty_paid: 0,
+        native_qty_released: 0,
+        native_fee_or_rebate: 0,
+        fill_count: 0,
+        filled_qty: Some(0),
+        origin: Some((ctx.entity_id(), instruction.name())),
+        origin_signature: Some(ctx.signature.clone()),
+        created_slot: ctx.slot,
+        updated_slot: ctx.slot,
+        updated_block_hash: ctx.block_hash.clone(),
+        updated_signature: ctx.signature.clone(),
+    })
+}
+
+/// Move orders through their lifecycle. New orders are opened before the events pushed by
+/// the same instruction are applied, cancels and prunes are applied after them. The v1
+/// cancels only queue a request, their order is closed by the Out event of the request.
+pub fn process_instruction(
+    ctx: &InstructionContext,
+    account_infos: &AccountInfos,
+    accounts: &[Pubkey],
+    instruction: &MarketInstruction,
+    events: &[QueueEvent],
+) {
+    let coin_lot_size = events
+        .first()
+        .and_then(|queue_event| market::market_info(&queue_event.market))
+        .map(|info| info.coin_lot_size)
+        .filter(|coin_lot_size| *coin_lot_size > 0);
+    let mut tracker = ORDERS.lock().unwrap();
+    let mut updated = Vec::new();
+    let placed = new_order(ctx, accounts, instruction);
+    // Balances report read errors, an unreadable account only leaves the order id unknown
+    let placed_state = placed
+        .as_ref()
+        .filter(|_| account_infos.is_available())
+        .and_then(|order| account_infos.open_orders(&order.open_orders, ctx.slot).ok())
+        .flatten();
+    let placed_id = placed.map(|order| {
+        let order = tracker.place(order, events, placed_state.as_ref());
+        let id = order.id.clone();
+        updated.push(order);
+        id
+    });
+    let out_status = match instruction {
+        MarketInstruction::Prune(_) => OrderStatus::Pruned,
+        _ => OrderStatus::Cancelled,
+    };
+    for event in events {
+        updated.extend(tracker.apply_event(ctx, event, out_status, coin_lot_size));
+    }
+    if let Some(id) = placed_id {
+        updated.extend(tracker.close_placed(ctx, &id, placed_state.as_ref()));
+    }
+    let market = accounts.first();
+    let closed = match instruction {
+        MarketInstruction::CancelOrderV2(arg) => market
+            .and_then(|market| tracker.cancel_by_order_id(ctx, market, arg.order_id))
+            .into_iter()
+            .collect(),
+        MarketInstruction::CancelOrderByClientIdV2(arg) => accounts
+            .get(3)
+            .and_then(|open_orders| tracker.cancel_by_client_id(ctx, open_orders, *arg))
+            .into_iter()
+            .collect(),
+        MarketInstruction::Prune(_) => match (market, accounts.get(4)) {
+            (Some(market), Some(open_orders)) => tracker.prune(ctx, market, open_orders),
+            _ => Vec::new(),
+        },
+        _ => Vec::new(),
+    };
+    updated.extend(closed);
+    for order in updated {
+        save_order(&order);
+    }
+}
+
+pub fn save_order(order: &Order) {
+    Entity::from(order_attributes(order)).save("Order");
+}
+
+pub fn order_attributes(order: &Order) -> HashMap<Attribute, Value> {
+    let mut map: HashMap<Attribute, Value> = HashMap::default();
+    map.insert("id".to_string(), Value::from(order.id.clone()));
+    map.insert("market".to_string(), Value::from(order.market.to_string()));
+    map.insert(
+        "open_orders".to_string(),
+        Value::from(order.open_orders.to_string()),
+    );
+    map.insert(
+        "side".to_string(),
+        Value::from(serde_json::to_string(&order.side).unwrap_or_default()),
+    );
+    match order.order_id {
+        Some(id) => order_id::insert_order_id(&mut map, id, &order.side),
+        None => {
+            map.insert("order_id".to_string(), Value::Null);
+            map.insert("price_lots".to_string(), Value::Null);
+            map.insert("seq_num".to_string(), Value::Null);
+        }
+    }
+    map.insert(
+        "client_order_id".to_string(),
+        Value::from(order.client_order_id),
+    );
+    map.insert("limit_price".to_string(), Value::from(order.limit_price));
+    map.insert(
+        "max_qty".to_string(),
+        order.max_qty.map(Value::from).unwrap_or(Value::Null),
+    );
+    market::insert_price_size(
+        &mut map,
+        Some(&order.market),
+        order.limit_price,
+        order.max_qty,
+    );
+    map.insert(
+        "order_type".to_string(),
+        order
+            .order_type
+            .as_ref()
+            .map(|order_type| Value::from(serde_json::to_string(order_type).unwrap_or_default()))
+            .unwrap_or(Value::Null),
+    );
+    map.insert(
+        "status".to_string(),
+        Value::from(order.status.as_str().to_string()),
+    );
+    map.insert(
+        "native_qty_paid".to_string(),
+        Value::from(order.native_qty_paid),
+    );
+    map.insert(
+        "native_qty_released".to_string(),
+        Value::from(order.native_qty_released),
+    );
+    map.insert(
+        "native_fee_or_rebate".to_string(),
+        Value::from(order.native_fee_or_rebate),
+    );
+    map.insert("fill_count".to_string(), Value::from(order.fill_count));
+    map.insert(
+        "filled_qty".to_string(),
+        order.filled_qty.map(Value::from).unwrap_or(Value::Null),
+    );
+    map.insert(
+        "origin".to_string(),
+        order
+            .origin
+            .as_ref()
+            .map(|(id, _)| Value::from(id.clone()))
+            .unwrap_or(Value::Null),
+    );
+    map.insert(
+        "origin_type".to_string(),
+        order
+            .origin
+            .as_ref()
+            .map(|(_, entity_name)| Value::from(entity_name.to_string()))
+            .unwrap_or(Value::Null),
+    );
+    map.insert(
+        "origin_signature".to_string(),
+        order
+            .origin_signature
+            .as_ref()
+            .map(|signature| Value::from(signature.clone()))
+            .unwrap_or(Value::Null),
+    );
+    map.insert("created_slot".to_string(), Value::from(order.created_slot));
+    map.insert("updated_slot".to_string(), Value::from(order.updated_slot));
+    map.insert(
+        "updated_block_hash".to_string(),
+        Value::from(order.updated_block_hash.clone()),
+    );
+    map.insert(
+        "updated_signature".to_string(),
+        Value::from(order.updated_signature.clone()),
+    );
+    map
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::account_source::{AccountInfos, MemorySource};
+    use crate::batch;
+    use crate::derived;
+    use crate::derived::market::{MarketInfo, MARKETS};
+    use crate::generated::instruction::*;
+    use crate::state::event_queue::{event_flag, Event};
+    use crate::state::market::MarketState;
+    use crate::state::request_queue::{request_flag, Request};
+    use crate::testing::{
+        ctx, event_queue_data, fixture, key, lock_state, open_orders_data, request_queue_data,
+        stored, MARKET_V3,
+    };
+    use massbit_solana_sdk::entity::Value;
+    use solana_program::message::Message;
+    use solana_program::pubkey::Pubkey;
+    use std::num::NonZeroU64;
+    use std::sync::Arc;
+
+    const COIN_LOT_SIZE: u64 = 10;
+
+    /// Register `market` with its event queue, as the v3 fixture market with a coin lot size
+    /// of `COIN_LOT_SIZE`.
+    fn register_market(market: Pubkey, event_queue: Pubkey) {
+        let mut state = MarketState::unpack(&fixture(MARKET_V3)).unwrap();
+        state.own_address = market;
+        state.event_queue = event_queue;
+        state.coin_lot_size = COIN_LOT_SIZE;
+        MARKETS
+            .lock()
+            .unwrap()
+            .register(MarketInfo::from_state(&ctx(1, 0), &market, &state));
+    }
+
+    fn new_order(side: Side, order_type: OrderType, client_order_id: u64) -> MarketInstruction {
+        MarketInstruction::NewOrderV3(NewOrderInstructionV3 {
+            side,
+            limit_price: NonZeroU64::new(50).unwrap(),
+            max_coin_qty: NonZeroU64::new(3).unwrap(),
+            max_native_pc_qty_including_fees: NonZeroU64::new(1_000).unwrap(),
+            self_trade_behavior: SelfTradeBehavior::DecrementTake,
+            order_type,
+            client_order_id,
+            limit: 10,
+        })
+    }
+
+    /// Fill of `lots` coin lots on the `side` of `owner`.
+    fn fill(
+        owner: Pubkey,
+        side: Side,
+        maker: bool,
+        order_id: u128,
+        client_order_id: u64,
+        lots: u64,
+    ) -> Event {
+        let mut event_flags = event_flag::FILL;
+        if maker {
+            event_flags |= event_flag::MAKER;
+        }
+        let (native_qty_released, native_qty_paid) = match side {
+            Side::Bid => {
+                event_flags |= event_flag::BID;
+                (lots * COIN_LOT_SIZE, lots * 50)
+            }
+            Side::Ask => (lots * 50, lots * COIN_LOT_SIZE),
+        };
+        Event {
+            event_flags,
+            owner_slot: 0,
+            fee_tier: 0,
+            native_qty_released,
+            native_qty_paid,
+            native_fee_or_rebate: 0,
+            order_id,
+            owner,
+            client_order_id,
+        }
+    }
+
+    fn place(
+        account_infos: &AccountInfos,
+        slot: u64,
+        accounts: &[Pubkey],
+        instruction: &MarketInstruction,
+    ) {
+        derived::process_instruction(
+            &ctx(slot, 0),
+            account_infos,
+            &Message::default(),
+            accounts,
+            instruction,
+        )
+        .unwrap();
+        batch::commit(&format!("hash-{}", slot), slot).unwrap();
+    }
+
+    fn status(id: &str) -> Option<Value> {
+        stored("Order")[id].get("status").cloned()
+    }
+
+    #[test]
+    fn tracks_filled_quantity_until_filled() {
+        let _state = lock_state();
+        let (market, event_queue, bidder, asker) = (key(70), key(71), key(72), key(73));
+        register_market(market, event_queue);
+        let bid_id = 50 << 64 | !1u64 as u128;
+        let source = MemorySource::default();
+        source.insert(event_queue, 299, event_queue_data(10, &[]));
+        source.insert(
+            event_queue,
+            300,
+            event_queue_data(11, &[fill(bidder, Side::Bid, false, bid_id, 7, 1)]),
+        );
+        source.insert(
+            event_queue,
+            301,
+            event_queue_data(
+                13,
+                &[
+                    fill(bidder, Side::Bid, true, bid_id, 7, 2),
+                    fill(asker, Side::Ask, false, 50 << 64 | 2, 8, 2),
+                ],
+            ),
+        );
+        let account_infos = AccountInfos::new(Some(Arc::new(source)));
+
+        place(
+            &account_infos,
+            300,
+            &[market, bidder, key(74), event_queue],
+            &new_order(Side::Bid, OrderType::Limit, 7),
+        );
+        let id = format!("{}-{}", market, bid_id);
+        assert_eq!(status(&id), Some(Value::from("partially_filled")));
+        assert_eq!(
+            stored("Order")[&id].get("filled_qty"),
+            Some(&Value::from(1u64))
+        );
+
+        place(
+            &account_infos,
+            301,
+            &[market, asker, key(74), event_queue],
+            &new_order(Side::Ask, OrderType::Limit, 8),
+        );
+        assert_eq!(status(&id), Some(Value::from("filled")));
+        assert_eq!(
+            stored("Order")[&id].get("filled_qty"),
+            Some(&Value::from(3u64))
+        );
+    }
+
+    #[test]
+    fn closes_immediate_or_cancel_and_rejected_post_only_orders() {
+        let _state = lock_state();
+        let (market, event_queue) = (key(75), key(76));
+        let (taker, rejected, resting) = (key(77), key(78), key(79));
+        register_market(market, event_queue);
+        let resting_id = 50 << 64 | !5u64 as u128;
+        let source = MemorySource::default();
+        source.insert(event_queue, 309, event_queue_data(20, &[]));
+        source.insert(
+            event_queue,
+            310,
+            event_queue_data(
+                21,
+                &[fill(
+                    taker,
+                    Side::Bid,
+                    false,
+                    50 << 64 | !4u64 as u128,
+                    1,
+                    1,
+                )],
+            ),
+        );
+        source.insert(rejected, 311, open_orders_data(market, key(2), &[]));
+        source.insert(
+            resting,
+            312,
+            open_orders_data(market, key(2), &[(Side::Ask, resting_id, 0)]),
+        );
+        let account_infos = AccountInfos::new(Some(Arc::new(source)));
+
+        place(
+            &account_infos,
+            310,
+            &[market, taker, key(74), event_queue],
+            &new_order(Side::Bid, OrderType::ImmediateOrCancel, 1),
+        );
+        assert_eq!(
+            status(&format!("{}-{}", market, 50 << 64 | !4u64 as u128)),
+            Some(Value::from("cancelled"))
+        );
+
+        place(
+            &account_infos,
+            311,
+            &[market, rejected, key(74), event_queue],
+            &new_order(Side::Bid, OrderType::PostOnly, 2),
+        );
+        // Without an order id the order keeps the id of the instruction that placed it
+        assert_eq!(
+            status(&ctx(311, 0).entity_id()),
+            Some(Value::from("cancelled"))
+        );
+
+        // A resting order is keyed by the order id read from its account
+        place(
+            &account_infos,
+            312,
+            &[market, resting, key(74), event_queue],
+            &new_order(Side::Ask, OrderType::PostOnly, 0),
+        );
+        let id = format!("{}-{}", market, resting_id);
+        assert_eq!(status(&id), Some(Value::from("open")));
+    }
+
+    #[test]
+    fn placed_and_filled_orders_share_their_row() {
+        let _state = lock_state();
+        let (market, event_queue, maker, taker) = (key(80), key(81), key(82), key(83));
+        register_market(market, event_queue);
+        let maker_id = 50 << 64 | !6u64 as u128;
+        let source = MemorySource::default();
+        source.insert(event_queue, 319, event_queue_data(30, &[]));
+        source.insert(
+            event_queue,
+            321,
+            event_queue_data(
+                32,
+                &[
+                    fill(maker, Side::Ask, true, maker_id, 0, 1),
+                    fill(taker, Side::Bid, false, 50 << 64 | 7, 0, 1),
+                ],
+            ),
+        );
+        source.insert(
+            maker,
+            320,
+            open_orders_data(market, key(2), &[(Side::Ask, maker_id, 0)]),
+        );
+        let account_infos = AccountInfos::new(Some(Arc::new(source)));
+
+        place(
+            &account_infos,
+            320,
+            &[market, maker, key(74), event_queue],
+            &new_order(Side::Ask, OrderType::Limit, 0),
+        );
+        place(
+            &account_infos,
+            321,
+            &[market, taker, key(74), event_queue],
+            &new_order(Side::Bid, OrderType::Limit, 0),
+        );
+        let orders = stored("Order");
+        let placed: Vec<_> = orders
+            .values()
+            .filter(|order| order.get("open_orders") == Some(&Value::from(maker.to_string())))
+            .collect();
+        assert_eq!(placed.len(), 1);
+        let id = format!("{}-{}", market, maker_id);
+        assert_eq!(placed[0].get("id"), Some(&Value::from(id)));
+        assert_eq!(placed[0].get("fill_count"), Some(&Value::from(1u64)));
+        assert_eq!(
+            placed[0].get("status"),
+            Some(&Value::from("partially_filled"))
+        );
+    }
+
+    #[test]
+    fn reused_client_order_ids_keep_their_own_rows() {
+        let _state = lock_state();
+        let (market, event_queue, owner) = (key(84), key(85), key(86));
+        register_market(market, event_queue);
+        let (first_id, second_id) = (50 << 64 | 8, 50 << 64 | 9);
+        let source = MemorySource::default();
+        source.insert(event_queue, 329, event_queue_data(40, &[]));
+        source.insert(
+            owner,
+            330,
+            open_orders_data(market, key(2), &[(Side::Ask, first_id, 5)]),
+        );
+        source.insert(
+            owner,
+            331,
+            open_orders_data(
+                market,
+                key(2),
+                &[(Side::Ask, first_id, 5), (Side::Ask, second_id, 5)],
+            ),
+        );
+        let account_infos = AccountInfos::new(Some(Arc::new(source)));
+
+        for slot in [330, 331] {
+            place(
+                &account_infos,
+                slot,
+                &[market, owner, key(74), event_queue],
+                &new_order(Side::Ask, OrderType::Limit, 5),
+            );
+        }
+        for order_id in [first_id, second_id] {
+            let id = format!("{}-{}", market, order_id);
+            assert_eq!(status(&id), Some(Value::from("open")));
+        }
+    }
+
+    #[test]
+    fn queued_cancels_close_orders_at_their_out_event() {
+        let _state = lock_state();
+        let (market, event_queue, owner, request_queue) = (key(87), key(88), key(89), key(98));
+        register_market(market, event_queue);
+        let order_id = 50 << 64 | 10;
+        let cancel = Request {
+            request_flags: request_flag::CANCEL_ORDER,
+            owner_slot: 0,
+            fee_tier: 0,
+            self_trade_behavior: 0,
+            max_coin_qty_or_cancel_id: 0,
+            native_pc_qty_locked: 0,
+            order_id,
+            owner,
+            client_order_id: 0,
+        };
+        let out = Event {
+            event_flags: event_flag::OUT | event_flag::RELEASE_FUNDS,
+            owner_slot: 0,
+            fee_tier: 0,
+            native_qty_released: 3 * COIN_LOT_SIZE,
+            native_qty_paid: 0,
+            native_fee_or_rebate: 0,
+            order_id,
+            owner,
+            client_order_id: 6,
+        };
+        let source = MemorySource::default();
+        source.insert(event_queue, 339, event_queue_data(50, &[]));
+        source.insert(event_queue, 342, event_queue_data(51, &[out]));
+        let requests = [
+            cancel.clone(),
+            Request {
+                order_id: 0,
+                ..cancel
+            },
+        ];
+        source.insert(request_queue, 341, request_queue_data(0, 1, &requests));
+        source.insert(request_queue, 342, request_queue_data(1, 0, &requests));
+        source.insert(
+            owner,
+            340,
+            open_orders_data(market, key(2), &[(Side::Ask, order_id, 6)]),
+        );
+        let account_infos = AccountInfos::new(Some(Arc::new(source)));
+        let id = format!("{}-{}", market, order_id);
+
+        place(
+            &account_infos,
+            340,
+            &[market, owner, key(74), event_queue],
+            &new_order(Side::Ask, OrderType::Limit, 6),
+        );
+        // The cancel only queues a request, MatchOrders pops it
+        place(
+            &account_infos,
+            341,
+            &[market, owner, request_queue, key(74)],
+            &MarketInstruction::CancelOrder(CancelOrderInstruction {
+                side: Side::Ask,
+                order_id,
+                owner,
+                owner_slot: 0,
+            }),
+        );
+        assert_eq!(status(&id), Some(Value::from("open")));
+        place(
+            &account_infos,
+            342,
+            &[market, request_queue, event_queue],
+            &MarketInstruction::MatchOrders(1),
+        );
+        assert_eq!(status(&id), Some(Value::from("cancelled")));
+    }
+}
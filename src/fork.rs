@@ -0,0 +1,45 @@
+//! Detection of reorganizations from the parent of each indexed block.
+use crate::derived::journal::ROLLBACK_DEPTH;
+use lazy_static::lazy_static;
+use massbit_solana_sdk::types::SolanaBlock;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+lazy_static! {
+    /// Block hash of the indexed blocks of the last `ROLLBACK_DEPTH` slots, by slot.
+    static ref CHAIN: Mutex<BTreeMap<u64, String>> = Mutex::new(BTreeMap::new());
+}
+
+/// Slot and block hash to roll back to before indexing `block`, `None` when it extends the
+/// indexed chain. A block whose parent was indexed with another hash belongs to a fork that
+/// diverged further back than the blocks handed over, which cannot be told apart.
+pub fn fork_point(block: &SolanaBlock) -> Result<Option<(u64, String)>, String> {
+    let chain = CHAIN.lock().unwrap();
+    let parent_slot = block.block.parent_slot;
+    let previous_blockhash = &block.block.previous_blockhash;
+    if let Some(indexed) = chain.get(&parent_slot) {
+        if indexed != previous_blockhash {
+            return Err(format!(
+                "Block {} builds on {} at slot {}, indexed as {}",
+                block.block_number, previous_blockhash, parent_slot, indexed
+            ));
+        }
+    }
+    if chain.range(parent_slot + 1..).next().is_none() {
+        return Ok(None);
+    }
+    Ok(Some((parent_slot, previous_blockhash.clone())))
+}
+
+/// Record `block` as the tip of the indexed chain.
+pub fn extend(block: &SolanaBlock) {
+    let mut chain = CHAIN.lock().unwrap();
+    chain.insert(block.block_number, block.block.blockhash.clone());
+    let oldest = block.block_number.saturating_sub(ROLLBACK_DEPTH);
+    *chain = chain.split_off(&oldest);
+}
+
+/// Forget the blocks above `slot`.
+pub fn truncate(slot: u64) {
+    CHAIN.lock().unwrap().split_off(&(slot + 1));
+}
@@ -1,394 +1,271 @@
+use crate::program::DexVersion;
 use arrayref::{array_ref, array_refs};
 use serde::{Deserialize, Serialize};
+use solana_program::pubkey::Pubkey;
 use std::num::*;
+use thiserror::Error;
 
-#[derive(Clone, PartialEq, Debug, Deserialize, Serialize)]
-pub struct CancelOrderInstruction {
-    pub side: Side,
-    pub order_id: u128,
-    pub owner: Vec<u64>,
-    pub owner_slot: u8,
+#[derive(Clone, PartialEq, Debug, Error)]
+pub enum DecodeError {
+    #[error("input too short, expected {expected} bytes but got {actual}")]
+    TooShort { expected: usize, actual: usize },
+    #[error("unknown instruction tag {0}")]
+    UnknownTag(u32),
+    #[error("invalid tag {tag} for enum {name}")]
+    BadEnumTag { name: &'static str, tag: u32 },
+    #[error("field {0} must be non zero")]
+    ZeroField(&'static str),
+    #[error("unsupported instruction version {0}")]
+    UnsupportedVersion(u8),
 }
-impl CancelOrderInstruction {
-    pub fn unpack(input: &[u8; 53]) -> Option<Self> {
-        let (side, order_id, owner, owner_slot) = array_refs![input, 4, 16, 32, 1];
-        let side = Side::unpack(side);
-        if side.is_some() {
-            Some(CancelOrderInstruction {
-                side: side.unwrap(),
-                order_id: u128::from_le_bytes(*order_id),
-                owner: {
-                    let arr = array_refs![owner, 8, 8, 8, 8];
-                    vec![
-                        u64::from_le_bytes(*arr.0),
-                        u64::from_le_bytes(*arr.1),
-                        u64::from_le_bytes(*arr.2),
-                        u64::from_le_bytes(*arr.3),
-                    ]
-                },
-                owner_slot: u8::from_le_bytes(*owner_slot),
-            })
-        } else {
-            None
+impl DecodeError {
+    /// Label of the error kind in metrics.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            DecodeError::TooShort { .. } => "too_short",
+            DecodeError::UnknownTag(_) => "unknown_tag",
+            DecodeError::BadEnumTag { .. } => "bad_enum_tag",
+            DecodeError::ZeroField(_) => "zero_field",
+            DecodeError::UnsupportedVersion(_) => "unsupported_version",
         }
     }
 }
-#[derive(Clone, PartialEq, Debug, Deserialize, Serialize)]
-pub struct CancelOrderInstructionV2 {
-    pub side: Side,
-    pub order_id: u128,
+/// Instruction layout versions understood by the decoder, Serum only ships version 0.
+pub const SUPPORTED_INSTRUCTION_VERSIONS: &[u8] = &[0];
+fn take(data: &[u8], len: usize) -> Result<&[u8], DecodeError> {
+    data.get(..len).ok_or(DecodeError::TooShort {
+        expected: len,
+        actual: data.len(),
+    })
 }
-impl CancelOrderInstructionV2 {
-    pub fn unpack(input: &[u8; 20]) -> Option<Self> {
-        let (side, order_id) = array_refs![input, 4, 16];
-        let side = Side::unpack(side);
-        if side.is_some() {
-            Some(CancelOrderInstructionV2 {
-                side: side.unwrap(),
-                order_id: u128::from_le_bytes(*order_id),
-            })
-        } else {
-            None
-        }
-    }
+fn non_zero_u64(name: &'static str, input: &[u8; 8]) -> Result<NonZeroU64, DecodeError> {
+    NonZeroU64::new(u64::from_le_bytes(*input)).ok_or(DecodeError::ZeroField(name))
 }
-#[derive(Clone, PartialEq, Debug, Deserialize, Serialize)]
-pub struct InitializeMarketInstruction {
-    pub coin_lot_size: u64,
-    pub pc_lot_size: u64,
-    pub fee_rate_bps: u16,
-    pub vault_signer_nonce: u64,
-    pub pc_dust_threshold: u64,
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct VersionedMarketInstruction {
+    pub version: u8,
+    pub instruction: MarketInstruction,
 }
-impl InitializeMarketInstruction {
-    pub fn unpack(input: &[u8; 34]) -> Option<Self> {
-        let (coin_lot_size, pc_lot_size, fee_rate_bps, vault_signer_nonce, pc_dust_threshold) =
-            array_refs![input, 8, 8, 2, 8, 8];
-
-        Some(InitializeMarketInstruction {
-            coin_lot_size: u64::from_le_bytes(*coin_lot_size),
-            pc_lot_size: u64::from_le_bytes(*pc_lot_size),
-            fee_rate_bps: u16::from_le_bytes(*fee_rate_bps),
-            vault_signer_nonce: u64::from_le_bytes(*vault_signer_nonce),
-            pc_dust_threshold: u64::from_le_bytes(*pc_dust_threshold),
+impl MarketInstruction {
+    pub fn unpack(
+        input: &[u8],
+        version: DexVersion,
+    ) -> Result<VersionedMarketInstruction, DecodeError> {
+        let header = array_ref![take(input, 5)?, 0, 5];
+        let (&[offset], &tag_slice) = array_refs![header, 1, 4];
+        if !SUPPORTED_INSTRUCTION_VERSIONS.contains(&offset) {
+            return Err(DecodeError::UnsupportedVersion(offset));
+        }
+        let tag_val = u32::from_le_bytes(tag_slice);
+        if tag_val > version.max_instruction_tag() {
+            return Err(DecodeError::UnknownTag(tag_val));
+        }
+        Self::unpack_data(&input[5..], tag_val).map(|instruction| VersionedMarketInstruction {
+            version: offset,
+            instruction,
         })
     }
 }
-#[derive(Clone, PartialEq, Debug, Deserialize, Serialize)]
-pub struct NewOrderInstructionV1 {
-    pub side: Side,
-    pub limit_price: NonZeroU64,
-    pub max_qty: NonZeroU64,
-    pub order_type: OrderType,
-    pub client_id: u64,
-}
-impl NewOrderInstructionV1 {
-    pub fn unpack(input: &[u8; 32]) -> Option<Self> {
-        let (side, limit_price, max_qty, order_type, client_id) = array_refs![input, 4, 8, 8, 4, 8];
-        let side = Side::unpack(side);
-        let limit_price = NonZeroU64::new(u64::from_le_bytes(*limit_price));
-        let max_qty = NonZeroU64::new(u64::from_le_bytes(*max_qty));
-        let order_type = OrderType::unpack(order_type);
-        if side.is_some() && limit_price.is_some() && max_qty.is_some() && order_type.is_some() {
-            Some(NewOrderInstructionV1 {
-                side: side.unwrap(),
-                limit_price: limit_price.unwrap(),
-                max_qty: max_qty.unwrap(),
-                order_type: order_type.unwrap(),
-                client_id: u64::from_le_bytes(*client_id),
-            })
-        } else {
-            None
+
+// Instruction structs, enums and `MarketInstruction::unpack_data`, generated from
+// instruction.json by build.rs.
+include!(concat!(env!("OUT_DIR"), "/instruction.rs"));
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Payload length of every instruction tag.
+    pub(crate) const PAYLOAD_LENS: &[(u32, usize)] = &[
+        (0, 34),
+        (1, 32),
+        (2, 2),
+        (3, 2),
+        (4, 53),
+        (5, 0),
+        (6, 8),
+        (7, 0),
+        (8, 0),
+        (9, 36),
+        (10, 46),
+        (11, 20),
+        (12, 8),
+        (13, 46),
+        (14, 0),
+        (15, 0),
+        (16, 2),
+        (17, 2),
+    ];
+
+    /// Offsets of the `NonZeroU64` fields of the tags that have some.
+    fn non_zero_offsets(tag: u32) -> &'static [usize] {
+        match tag {
+            1 | 9 => &[4, 12],
+            10 | 13 => &[4, 12, 20],
+            _ => &[],
         }
     }
-}
-#[derive(Clone, PartialEq, Debug, Deserialize, Serialize)]
-pub struct NewOrderInstructionV2 {
-    pub side: Side,
-    pub limit_price: NonZeroU64,
-    pub max_qty: NonZeroU64,
-    pub order_type: OrderType,
-    pub client_id: u64,
-    pub self_trade_behavior: SelfTradeBehavior,
-}
-impl NewOrderInstructionV2 {
-    pub fn unpack(input: &[u8; 33]) -> Option<Self> {
-        let (side, limit_price, max_qty, order_type, client_id, self_trade_behavior) =
-            array_refs![input, 4, 8, 8, 4, 8, 1];
-        let side = Side::unpack(side);
-        let limit_price = NonZeroU64::new(u64::from_le_bytes(*limit_price));
-        let max_qty = NonZeroU64::new(u64::from_le_bytes(*max_qty));
-        let order_type = OrderType::unpack(order_type);
-        let self_trade_behavior = SelfTradeBehavior::unpack(self_trade_behavior);
-        if side.is_some()
-            && limit_price.is_some()
-            && max_qty.is_some()
-            && order_type.is_some()
-            && self_trade_behavior.is_some()
-        {
-            Some(NewOrderInstructionV2 {
-                side: side.unwrap(),
-                limit_price: limit_price.unwrap(),
-                max_qty: max_qty.unwrap(),
-                order_type: order_type.unwrap(),
-                client_id: u64::from_le_bytes(*client_id),
-                self_trade_behavior: self_trade_behavior.unwrap(),
-            })
-        } else {
-            None
+
+    pub(crate) fn instruction(version: u8, tag: u32, payload: &[u8]) -> Vec<u8> {
+        let mut input = vec![version];
+        input.extend_from_slice(&tag.to_le_bytes());
+        input.extend_from_slice(payload);
+        input
+    }
+
+    /// Smallest valid payload of `tag`: enums at their first variant, non zero fields at 1.
+    pub(crate) fn valid_payload(tag: u32, len: usize) -> Vec<u8> {
+        let mut payload = vec![0; len];
+        for offset in non_zero_offsets(tag) {
+            payload[*offset..*offset + 8].copy_from_slice(&1u64.to_le_bytes());
         }
+        payload
     }
-}
-#[derive(Clone, PartialEq, Debug, Deserialize, Serialize)]
-pub struct NewOrderInstructionV3 {
-    pub side: Side,
-    pub limit_price: NonZeroU64,
-    pub max_coin_qty: NonZeroU64,
-    pub max_native_pc_qty_including_fees: NonZeroU64,
-    pub self_trade_behavior: SelfTradeBehavior,
-    pub order_type: OrderType,
-    pub client_order_id: u64,
-    pub limit: u16,
-}
-impl NewOrderInstructionV3 {
-    pub fn unpack(input: &[u8; 46]) -> Option<Self> {
-        let (
-            side,
-            limit_price,
-            max_coin_qty,
-            max_native_pc_qty_including_fees,
-            self_trade_behavior,
-            order_type,
-            client_order_id,
-            limit,
-        ) = array_refs![input, 4, 8, 8, 8, 4, 4, 8, 2];
-        let side = Side::unpack(side);
-        let limit_price = NonZeroU64::new(u64::from_le_bytes(*limit_price));
-        let max_coin_qty = NonZeroU64::new(u64::from_le_bytes(*max_coin_qty));
-        let max_native_pc_qty_including_fees =
-            NonZeroU64::new(u64::from_le_bytes(*max_native_pc_qty_including_fees));
-        let self_trade_behavior = SelfTradeBehavior::unpack(self_trade_behavior);
-        let order_type = OrderType::unpack(order_type);
-        if side.is_some()
-            && limit_price.is_some()
-            && max_coin_qty.is_some()
-            && max_native_pc_qty_including_fees.is_some()
-            && self_trade_behavior.is_some()
-            && order_type.is_some()
-        {
-            Some(NewOrderInstructionV3 {
-                side: side.unwrap(),
-                limit_price: limit_price.unwrap(),
-                max_coin_qty: max_coin_qty.unwrap(),
-                max_native_pc_qty_including_fees: max_native_pc_qty_including_fees.unwrap(),
-                self_trade_behavior: self_trade_behavior.unwrap(),
-                order_type: order_type.unwrap(),
-                client_order_id: u64::from_le_bytes(*client_order_id),
-                limit: u16::from_le_bytes(*limit),
-            })
-        } else {
-            None
+
+    #[test]
+    fn decodes_every_tag() {
+        for (tag, len) in PAYLOAD_LENS {
+            let input = instruction(0, *tag, &valid_payload(*tag, *len));
+            let decoded = MarketInstruction::unpack(&input, DexVersion::V3)
+                .unwrap_or_else(|err| panic!("tag {}: {}", tag, err));
+            assert_eq!(decoded.version, 0);
         }
     }
-}
-#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
-pub enum OrderType {
-    Limit,
-    ImmediateOrCancel,
-    PostOnly,
-}
-impl OrderType {
-    pub fn unpack(input: &[u8]) -> Option<Self> {
-        let (&tag_slice, data) = array_refs![input, 4; ..;];
-        let tag_val = u32::from_le_bytes(tag_slice) as u32;
-        match tag_val {
-            0 => Some(OrderType::Limit),
-            1 => Some(OrderType::ImmediateOrCancel),
-            2 => Some(OrderType::PostOnly),
-            _ => None,
+
+    #[test]
+    fn ignores_trailing_bytes() {
+        for (tag, len) in PAYLOAD_LENS {
+            let mut payload = valid_payload(*tag, *len);
+            payload.extend_from_slice(&[0xff; 64]);
+            let input = instruction(0, *tag, &payload);
+            assert!(MarketInstruction::unpack(&input, DexVersion::V3).is_ok());
         }
     }
-}
-#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
-pub enum SelfTradeBehavior {
-    DecrementTake,
-    CancelProvide,
-    AbortTransaction,
-}
-impl SelfTradeBehavior {
-    pub fn unpack(input: &[u8]) -> Option<Self> {
-        let (&tag_slice, data) = array_refs![input, 4; ..;];
-        let tag_val = u32::from_le_bytes(tag_slice) as u32;
-        match tag_val {
-            0 => Some(SelfTradeBehavior::DecrementTake),
-            1 => Some(SelfTradeBehavior::CancelProvide),
-            2 => Some(SelfTradeBehavior::AbortTransaction),
-            _ => None,
+
+    #[test]
+    fn rejects_truncated_payloads() {
+        for (tag, len) in PAYLOAD_LENS.iter().filter(|(_, len)| *len > 0) {
+            for actual in [0, len - 1] {
+                let input = instruction(0, *tag, &valid_payload(*tag, *len)[..actual]);
+                assert_eq!(
+                    MarketInstruction::unpack(&input, DexVersion::V3),
+                    Err(DecodeError::TooShort {
+                        expected: *len,
+                        actual
+                    }),
+                    "tag {}",
+                    tag
+                );
+            }
         }
     }
-}
-#[derive(Clone, PartialEq, Debug, Deserialize, Serialize)]
-pub struct SendTakeInstruction {
-    pub side: Side,
-    pub limit_price: NonZeroU64,
-    pub max_coin_qty: NonZeroU64,
-    pub max_native_pc_qty_including_fees: NonZeroU64,
-    pub min_coin_qty: u64,
-    pub min_native_pc_qty: u64,
-    pub limit: u16,
-}
-impl SendTakeInstruction {
-    pub fn unpack(input: &[u8; 46]) -> Option<Self> {
-        let (
-            side,
-            limit_price,
-            max_coin_qty,
-            max_native_pc_qty_including_fees,
-            min_coin_qty,
-            min_native_pc_qty,
-            limit,
-        ) = array_refs![input, 4, 8, 8, 8, 8, 8, 2];
-        let side = Side::unpack(side);
-        let limit_price = NonZeroU64::new(u64::from_le_bytes(*limit_price));
-        let max_coin_qty = NonZeroU64::new(u64::from_le_bytes(*max_coin_qty));
-        let max_native_pc_qty_including_fees =
-            NonZeroU64::new(u64::from_le_bytes(*max_native_pc_qty_including_fees));
-        if side.is_some()
-            && limit_price.is_some()
-            && max_coin_qty.is_some()
-            && max_native_pc_qty_including_fees.is_some()
-        {
-            Some(SendTakeInstruction {
-                side: side.unwrap(),
-                limit_price: limit_price.unwrap(),
-                max_coin_qty: max_coin_qty.unwrap(),
-                max_native_pc_qty_including_fees: max_native_pc_qty_including_fees.unwrap(),
-                min_coin_qty: u64::from_le_bytes(*min_coin_qty),
-                min_native_pc_qty: u64::from_le_bytes(*min_native_pc_qty),
-                limit: u16::from_le_bytes(*limit),
+
+    #[test]
+    fn rejects_truncated_header() {
+        for len in 0..5 {
+            assert_eq!(
+                MarketInstruction::unpack(&[0; 5][..len], DexVersion::V3),
+                Err(DecodeError::TooShort {
+                    expected: 5,
+                    actual: len
+                })
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_tags() {
+        let input = instruction(0, 18, &[0; 64]);
+        assert_eq!(
+            MarketInstruction::unpack(&input, DexVersion::V3),
+            Err(DecodeError::UnknownTag(18))
+        );
+        let input = instruction(0, u32::MAX, &[]);
+        assert_eq!(
+            MarketInstruction::unpack(&input, DexVersion::OpenBook),
+            Err(DecodeError::UnknownTag(u32::MAX))
+        );
+        // NewOrderV3 does not exist before v3
+        let input = instruction(0, 10, &valid_payload(10, 46));
+        assert_eq!(
+            MarketInstruction::unpack(&input, DexVersion::V1),
+            Err(DecodeError::UnknownTag(10))
+        );
+    }
+
+    #[test]
+    fn rejects_bad_enum_tags() {
+        let mut payload = valid_payload(10, 46);
+        payload[..4].copy_from_slice(&2u32.to_le_bytes());
+        assert_eq!(
+            MarketInstruction::unpack(&instruction(0, 10, &payload), DexVersion::V3),
+            Err(DecodeError::BadEnumTag {
+                name: "Side",
+                tag: 2
+            })
+        );
+        let mut payload = valid_payload(10, 46);
+        payload[32..36].copy_from_slice(&3u32.to_le_bytes());
+        assert_eq!(
+            MarketInstruction::unpack(&instruction(0, 10, &payload), DexVersion::V3),
+            Err(DecodeError::BadEnumTag {
+                name: "OrderType",
+                tag: 3
             })
-        } else {
-            None
+        );
+        let mut payload = valid_payload(10, 46);
+        payload[28..32].copy_from_slice(&3u32.to_le_bytes());
+        assert_eq!(
+            MarketInstruction::unpack(&instruction(0, 10, &payload), DexVersion::V3),
+            Err(DecodeError::BadEnumTag {
+                name: "SelfTradeBehavior",
+                tag: 3
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_zero_non_zero_fields() {
+        let fields = [
+            "limit_price",
+            "max_coin_qty",
+            "max_native_pc_qty_including_fees",
+        ];
+        for (offset, field) in non_zero_offsets(10).iter().zip(fields.iter()) {
+            let mut payload = valid_payload(10, 46);
+            payload[*offset..*offset + 8].copy_from_slice(&[0; 8]);
+            assert_eq!(
+                MarketInstruction::unpack(&instruction(0, 10, &payload), DexVersion::V3),
+                Err(DecodeError::ZeroField(field))
+            );
         }
+        let payload = vec![0; 32];
+        assert_eq!(
+            MarketInstruction::unpack(&instruction(0, 1, &payload), DexVersion::V3),
+            Err(DecodeError::ZeroField("limit_price"))
+        );
     }
-}
-#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
-pub enum Side {
-    Bid,
-    Ask,
-}
-impl Side {
-    pub fn unpack(input: &[u8]) -> Option<Self> {
-        let (&tag_slice, data) = array_refs![input, 4; ..;];
-        let tag_val = u32::from_le_bytes(tag_slice) as u32;
-        match tag_val {
-            0 => Some(Side::Bid),
-            1 => Some(Side::Ask),
-            _ => None,
+
+    #[test]
+    fn rejects_unsupported_versions() {
+        for version in [1, 2, u8::MAX] {
+            let input = instruction(version, 10, &valid_payload(10, 46));
+            assert_eq!(
+                MarketInstruction::unpack(&input, DexVersion::V3),
+                Err(DecodeError::UnsupportedVersion(version))
+            );
         }
     }
-}
-#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
-pub enum MarketInstruction {
-    InitializeMarket(InitializeMarketInstruction),
-    NewOrder(NewOrderInstructionV1),
-    MatchOrders(u16),
-    ConsumeEvents(u16),
-    CancelOrder(CancelOrderInstruction),
-    SettleFunds,
-    CancelOrderByClientId(u64),
-    DisableMarket,
-    SweepFees,
-    NewOrderV2(NewOrderInstructionV2),
-    NewOrderV3(NewOrderInstructionV3),
-    CancelOrderV2(CancelOrderInstructionV2),
-    CancelOrderByClientIdV2(u64),
-    SendTake(SendTakeInstruction),
-    CloseOpenOrders,
-    InitOpenOrders,
-    Prune(u16),
-    ConsumeEventsPermissioned(u16),
-}
-impl MarketInstruction {
-    pub fn unpack(input: &[u8]) -> Option<Self> {
-        let (&[offset], &tag_slice, data) = array_refs![input, 1, 4; ..;];
-        let tag_val = u32::from_le_bytes(tag_slice) as u32;
-        match tag_val {
-            0 => {
-                let field_slice = array_ref![data, 0, 34];
-                let inner = InitializeMarketInstruction::unpack(field_slice);
-                inner.and_then(|inner_val| Some(MarketInstruction::InitializeMarket(inner_val)))
-            }
-            1 => {
-                let field_slice = array_ref![data, 0, 32];
-                let inner = NewOrderInstructionV1::unpack(field_slice);
-                inner.and_then(|inner_val| Some(MarketInstruction::NewOrder(inner_val)))
-            }
-            2 => {
-                let field_slice = array_ref![data, 0, 2];
-                Some(MarketInstruction::MatchOrders(u16::from_le_bytes(
-                    *field_slice,
-                )))
-            }
-            3 => {
-                let field_slice = array_ref![data, 0, 2];
-                Some(MarketInstruction::ConsumeEvents(u16::from_le_bytes(
-                    *field_slice,
-                )))
-            }
-            4 => {
-                let field_slice = array_ref![data, 0, 53];
-                let inner = CancelOrderInstruction::unpack(field_slice);
-                inner.and_then(|inner_val| Some(MarketInstruction::CancelOrder(inner_val)))
-            }
-            5 => Some(MarketInstruction::SettleFunds),
-            6 => {
-                let field_slice = array_ref![data, 0, 8];
-                Some(MarketInstruction::CancelOrderByClientId(
-                    u64::from_le_bytes(*field_slice),
-                ))
-            }
-            7 => Some(MarketInstruction::DisableMarket),
-            8 => Some(MarketInstruction::SweepFees),
-            9 => {
-                let field_slice = array_ref![data, 0, 33];
-                let inner = NewOrderInstructionV2::unpack(field_slice);
-                inner.and_then(|inner_val| Some(MarketInstruction::NewOrderV2(inner_val)))
-            }
-            10 => {
-                let field_slice = array_ref![data, 0, 46];
-                let inner = NewOrderInstructionV3::unpack(field_slice);
-                inner.and_then(|inner_val| Some(MarketInstruction::NewOrderV3(inner_val)))
-            }
-            11 => {
-                let field_slice = array_ref![data, 0, 20];
-                let inner = CancelOrderInstructionV2::unpack(field_slice);
-                inner.and_then(|inner_val| Some(MarketInstruction::CancelOrderV2(inner_val)))
-            }
-            12 => {
-                let field_slice = array_ref![data, 0, 8];
-                Some(MarketInstruction::CancelOrderByClientIdV2(
-                    u64::from_le_bytes(*field_slice),
-                ))
-            }
-            13 => {
-                let field_slice = array_ref![data, 0, 46];
-                let inner = SendTakeInstruction::unpack(field_slice);
-                inner.and_then(|inner_val| Some(MarketInstruction::SendTake(inner_val)))
-            }
-            14 => Some(MarketInstruction::CloseOpenOrders),
-            15 => Some(MarketInstruction::InitOpenOrders),
-            16 => {
-                let field_slice = array_ref![data, 0, 2];
-                Some(MarketInstruction::Prune(u16::from_le_bytes(*field_slice)))
-            }
-            17 => {
-                let field_slice = array_ref![data, 0, 2];
-                Some(MarketInstruction::ConsumeEventsPermissioned(
-                    u16::from_le_bytes(*field_slice),
-                ))
+
+    #[test]
+    fn never_panics() {
+        for tag in 0..=20u32 {
+            for len in 0..=64 {
+                for fill in [0x00, 0x01, 0xff] {
+                    let input = instruction(0, tag, &vec![fill; len]);
+                    let _ = MarketInstruction::unpack(&input, DexVersion::V3);
+                }
             }
-            _ => None,
         }
     }
 }
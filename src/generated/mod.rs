@@ -1,2 +1,33 @@
 pub mod handler;
 pub mod instruction;
+
+/// Schema generated by build.rs: the instruction entities followed by `src/entities.graphql`.
+pub const SCHEMA: &str = include_str!(concat!(env!("OUT_DIR"), "/schema.graphql"));
+
+#[cfg(test)]
+mod tests {
+    use super::SCHEMA;
+    use std::env;
+    use std::fs;
+    use std::path::Path;
+
+    /// Copies of the schema deployed with the plugin.
+    const CHECKED_IN_SCHEMAS: &[&str] = &["src/schema.graphql", "releases/schema.graphql"];
+
+    /// Fails when `instruction.json` or `src/entities.graphql` changed without regenerating the
+    /// checked in schemas, which `SERUM_UPDATE_SCHEMA=1 cargo test schema` does.
+    #[test]
+    fn checked_in_schema_is_up_to_date() {
+        for schema in CHECKED_IN_SCHEMAS {
+            let path = Path::new(env!("CARGO_MANIFEST_DIR")).join(schema);
+            if env::var("SERUM_UPDATE_SCHEMA").is_ok() {
+                fs::write(&path, SCHEMA).unwrap();
+            }
+            assert!(
+                fs::read_to_string(&path).ok().as_deref() == Some(SCHEMA),
+                "{} is out of date, run `SERUM_UPDATE_SCHEMA=1 cargo test schema`",
+                schema
+            );
+        }
+    }
+}
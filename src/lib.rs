@@ -1,6 +1,20 @@
 
+pub mod account_source;
+pub mod batch;
+pub mod context;
+pub mod derived;
+pub mod fork;
 pub mod generated;
 pub mod mapping;
+pub mod metrics;
+pub mod order_id;
+pub mod program;
+pub mod state;
+#[cfg(test)]
+mod schema_check;
+#[cfg(test)]
+mod testing;
+pub mod token;
 
 use massbit_solana_sdk::{
     export_plugin,
@@ -8,18 +22,39 @@ use massbit_solana_sdk::{
     store::IndexStore,
     types::SolanaBlock,
 };
+use account_source::AccountSource;
+use batch::FlushMode;
+use derived::book::BookDetail;
 use lazy_static::lazy_static;
-use solana_client::rpc_client::RpcClient;
+use mapping::ErrorPolicy;
+use program::DexVersion;
+use solana_program::pubkey::Pubkey;
+use std::collections::HashMap;
 use std::env;
 use std::error::Error;
 use std::sync::Arc;
 
 lazy_static! {
-    pub static ref SOLANA_CLIENT: Arc<RpcClient> = Arc::new(RpcClient::new(
-        env::var("SOLANA_RPC_URL").unwrap_or(String::from("http://194.163.156.242:8899"))
-    ));
+    /// When set to `true`, transactions whose meta carries an error are not indexed at all.
+    /// Otherwise they are indexed with `success = false` and the error message.
+    pub static ref SKIP_FAILED_TRANSACTIONS: bool = env::var("SKIP_FAILED_TRANSACTIONS")
+        .map(|value| value.eq_ignore_ascii_case("true"))
+        .unwrap_or(false);
+    /// Indexed DEX programs and the layout version of each, see `program::load_program_ids`.
+    pub static ref PROGRAM_IDS: HashMap<Pubkey, DexVersion> = program::load_program_ids();
+    /// Where the account based derived state reads account data, see
+    /// `account_source::load_account_source`.
+    pub static ref ACCOUNT_SOURCE: Option<Arc<dyn AccountSource>> =
+        account_source::load_account_source();
+    /// Detail of the order book snapshots, books are not captured when unset.
+    pub static ref ORDER_BOOK: Option<BookDetail> = derived::book::load_book_detail();
+    /// Whether entities are committed after each block or once per `handle_blocks` call.
+    pub static ref FLUSH_MODE: FlushMode = batch::load_flush_mode();
+    /// Whether an instruction that cannot be indexed stops indexing or is recorded and skipped.
+    pub static ref ERROR_POLICY: ErrorPolicy = mapping::load_error_policy();
+    /// File the Prometheus metrics are written to after every `handle_blocks` call.
+    pub static ref METRICS_FILE: Option<String> = env::var("SERUM_METRICS_FILE").ok();
 }
-pub const ADDRESS: &str = "9xQeWvG816bUx9EPjHmaT23yvVM2ZWbrrpZb9PusVFin";
 
 #[doc(hidden)]
 #[no_mangle]
@@ -29,6 +64,10 @@ export_plugin!(register);
 
 #[allow(dead_code, improper_ctypes_definitions)]
 extern "C" fn register(registrar: &mut dyn PluginRegistrar) {
+    // Reject an invalid SERUM_PROGRAM_IDS when the plugin is loaded, not at the first block
+    lazy_static::initialize(&PROGRAM_IDS);
+    // Warn about the account source when the plugin is loaded
+    lazy_static::initialize(&ACCOUNT_SOURCE);
     registrar.register_solana_handler(Box::new(SolanaHandlerAdapter));
 }
 
@@ -37,17 +76,10 @@ pub struct SolanaHandlerAdapter;
 
 impl SolanaHandler for SolanaHandlerAdapter {
     fn handle_blocks(&self, blocks: &Vec<SolanaBlock>) -> Result<i64, Box<dyn Error>> {
-        let mut block_slot = -1_i64;
-        // Todo: Rewrite the flush so it will flush after finish the array of blocks for better performance. For now, we flush after each block.
-        for block in blocks {
-            mapping::handle_block(block);
-            block_slot = block_slot.max(block.block_number as i64);
-            unsafe {
-                if let Some(store) = &mut STORE {
-                    store.flush(&block.block.blockhash, block.block_number);
-                }
-            }
-        }
-        Ok(block_slot)
+        let block_slot = batch::index_blocks(blocks, *FLUSH_MODE, mapping::handle_block);
+        // Blocks committed before a failure are summarized and exported too
+        mapping::log_batch_summary(blocks);
+        metrics::write_file();
+        block_slot
     }
 }
@@ -1,56 +1,282 @@
+use crate::account_source::AccountInfos;
+use crate::context::InstructionContext;
 use crate::generated::handler::Handler;
-use crate::ADDRESS;
+use crate::metrics;
+use crate::{ACCOUNT_SOURCE, ERROR_POLICY, PROGRAM_IDS, SKIP_FAILED_TRANSACTIONS};
 use massbit_solana_sdk::types::SolanaBlock;
+use solana_program::instruction::CompiledInstruction;
+use solana_program::pubkey::Pubkey;
 use solana_transaction_status::TransactionWithStatusMeta;
+use std::env;
+use std::str::FromStr;
+
+/// What to do with an instruction that cannot be decoded, indexed or stored.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorPolicy {
+    /// Stop and return the error from `handle_blocks`, nothing of the failing block is committed.
+    FailFast,
+    /// Log the error, record the instruction as a `DeadLetter` and go on with the next
+    /// instruction.
+    Continue,
+}
+impl FromStr for ErrorPolicy {
+    type Err = String;
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().as_str() {
+            "fail_fast" => Ok(ErrorPolicy::FailFast),
+            "continue" => Ok(ErrorPolicy::Continue),
+            _ => Err(format!("Unknown error policy {}", value)),
+        }
+    }
+}
+
+/// Read `SERUM_ERROR_POLICY`, `continue` unless set to `fail_fast`.
+pub fn load_error_policy() -> ErrorPolicy {
+    env::var("SERUM_ERROR_POLICY")
+        .ok()
+        .and_then(|value| {
+            value
+                .parse()
+                .map_err(|err| log::warn!("Ignore SERUM_ERROR_POLICY: {}", err))
+                .ok()
+        })
+        .unwrap_or(ErrorPolicy::Continue)
+}
+
+/// Log one `key=value` record for a `handle_blocks` call, with the committed instructions by
+/// type as `decoded={NewOrderV3=2,CancelOrderV2=1}`.
+pub fn log_batch_summary(blocks: &[SolanaBlock]) {
+    let decoded = metrics::take_summary();
+    let first = blocks.iter().map(|block| block.block_number).min();
+    let last = blocks.iter().map(|block| block.block_number).max();
+    if let (Some(first), Some(last)) = (first, last) {
+        log::info!(
+            "first_slot={} last_slot={} blocks={} instructions={} decoded={{{}}} Indexed blocks",
+            first,
+            last,
+            blocks.len(),
+            decoded.values().sum::<u64>(),
+            decoded
+                .iter()
+                .map(|(instruction, count)| format!("{}={}", instruction, count))
+                .collect::<Vec<String>>()
+                .join(",")
+        );
+    }
+}
 
 pub fn handle_block(block: &SolanaBlock) -> Result<(), Box<dyn std::error::Error>> {
+    // Account data is read at the end of the block slot, so one cache serves the whole block
+    let account_infos = AccountInfos::new(ACCOUNT_SOURCE.clone());
     for (tx_ind, tran) in block.block.transactions.iter().enumerate() {
+        if *SKIP_FAILED_TRANSACTIONS && is_failed(tran) {
+            continue;
+        }
         if tran
             .transaction
             .message
             .account_keys
             .iter()
-            .any(|key| key.to_string().as_str() == ADDRESS)
+            .any(|key| PROGRAM_IDS.contains_key(key))
         {
-            parse_instructions(block, tran, tx_ind);
+            parse_instructions(block, tran, tx_ind, &account_infos)?;
         }
     }
     Ok(())
 }
-fn parse_instructions(block: &SolanaBlock, tran: &TransactionWithStatusMeta, tx_ind: usize) {
+fn is_failed(tran: &TransactionWithStatusMeta) -> bool {
+    tran.meta
+        .as_ref()
+        .map(|meta| meta.status.is_err())
+        .unwrap_or(false)
+}
+fn parse_instructions(
+    block: &SolanaBlock,
+    tran: &TransactionWithStatusMeta,
+    tx_ind: usize,
+    account_infos: &AccountInfos,
+) -> Result<(), anyhow::Error> {
+    let account_keys = tran.transaction.message.account_keys.as_slice();
     for (ind, inst) in tran.transaction.message.instructions.iter().enumerate() {
-        let program_key = inst.program_id(tran.transaction.message.account_keys.as_slice());
-        if program_key.to_string().as_str() == ADDRESS {
-            let mut accounts = Vec::default();
-            let mut work = |unique_ind: usize, acc_ind: usize| {
-                if let Some(key) = tran.transaction.message.account_keys.get(acc_ind) {
-                    accounts.push(key.clone());
-                };
-                Ok(())
-            };
-            inst.visit_each_account(&mut work);
-            // if let Some(account_infos) = SOLANA_CLIENT
-            //     .get_multiple_accounts_with_config(
-            //         accounts.as_slice(),
-            //         RpcAccountInfoConfig {
-            //             encoding: Some(UiAccountEncoding::JsonParsed),
-            //             commitment: None,
-            //             data_slice: None,
-            //         },
-            //     )
-            //     .map(|res| {
-            //         res.value
-            //             .into_iter()
-            //             .filter_map(|elm| elm)
-            //             .collect::<Vec<Account>>()
-            //     })
-            //     .ok()
-            // {
-            //println!("account_infos {:?}", &account_infos);
-            let handler = Handler {};
-            // Fixme: Get account_infos from chain take a lot of time. For now, use empty vector.
-            handler.process(block, tran, program_key, &accounts, inst.data.as_slice());
-            // }
+        let program_key = inst.program_id(account_keys);
+        if let Some(version) = PROGRAM_IDS.get(program_key) {
+            let ctx = InstructionContext::new(block, tran, tx_ind, ind, program_key, *version);
+            parse_instruction(block, tran, &ctx, account_infos, inst)?;
+        }
+    }
+    // Instructions invoked through CPI by aggregators and other programs
+    let inner_instructions = tran
+        .meta
+        .as_ref()
+        .and_then(|meta| meta.inner_instructions.as_ref());
+    for inner in inner_instructions.into_iter().flatten() {
+        let parent_ind = inner.index as usize;
+        let invoking_program = tran
+            .transaction
+            .message
+            .instructions
+            .get(parent_ind)
+            .map(|parent| *parent.program_id(account_keys));
+        for (inner_ind, inst) in inner.instructions.iter().enumerate() {
+            let program_key = inst.program_id(account_keys);
+            if let Some(version) = PROGRAM_IDS.get(program_key) {
+                let mut ctx =
+                    InstructionContext::new(block, tran, tx_ind, parent_ind, program_key, *version);
+                ctx.inner_index = Some(inner_ind);
+                ctx.invoking_program = invoking_program;
+                parse_instruction(block, tran, &ctx, account_infos, inst)?;
+            }
         }
     }
+    Ok(())
+}
+fn parse_instruction(
+    block: &SolanaBlock,
+    tran: &TransactionWithStatusMeta,
+    ctx: &InstructionContext,
+    account_infos: &AccountInfos,
+    inst: &CompiledInstruction,
+) -> Result<(), anyhow::Error> {
+    let account_keys = &tran.transaction.message.account_keys;
+    let accounts: Vec<Pubkey> = inst
+        .accounts
+        .iter()
+        .filter_map(|acc_ind| account_keys.get(*acc_ind as usize).copied())
+        .collect();
+    let handler = Handler {};
+    let result = handler.process(
+        block,
+        tran,
+        ctx,
+        &accounts,
+        account_infos,
+        inst.data.as_slice(),
+    );
+    match (result, *ERROR_POLICY) {
+        (Ok(()), _) => Ok(()),
+        // Entity ids are `<signature>-<instruction index>`, enough to find the instruction
+        (Err(err), ErrorPolicy::FailFast) => Err(err.context(format!(
+            "Cannot index instruction {} in block {}",
+            ctx.entity_id(),
+            block.block_number
+        ))),
+        (Err(err), ErrorPolicy::Continue) => {
+            log::error!(
+                "Cannot index instruction {} in block {}: {:#}",
+                ctx.entity_id(),
+                block.block_number,
+                err
+            );
+            handler.process_dead_letter(ctx, &accounts, inst.data.as_slice(), &err);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_instructions;
+    use crate::account_source::AccountInfos;
+    use crate::batch;
+    use crate::testing::{block, key, lock_state, stored};
+    use massbit_solana_sdk::entity::Value;
+    use solana_program::instruction::CompiledInstruction;
+    use solana_program::message::Message;
+    use solana_program::pubkey::Pubkey;
+    use solana_sdk::signature::Signature;
+    use solana_sdk::transaction::Transaction;
+    use solana_transaction_status::{
+        InnerInstructions, TransactionStatusMeta, TransactionWithStatusMeta,
+    };
+    use std::str::FromStr;
+
+    /// Serum v3 program.
+    const SERUM_V3: &str = "9xQeWvG816bUx9EPjHmaT23yvVM2ZWbrrpZb9PusVFin";
+
+    fn compiled(program_id_index: u8, accounts: &[u8], data: Vec<u8>) -> CompiledInstruction {
+        CompiledInstruction {
+            program_id_index,
+            accounts: accounts.to_vec(),
+            data,
+        }
+    }
+
+    /// Transaction of an aggregator that cancels an order through the DEX, followed by a
+    /// direct `SettleFunds`. Account keys: fee payer, aggregator, DEX, market, bids, asks,
+    /// open orders.
+    fn aggregator_transaction() -> TransactionWithStatusMeta {
+        let mut cancel = vec![0];
+        cancel.extend_from_slice(&12u32.to_le_bytes());
+        cancel.extend_from_slice(&7u64.to_le_bytes());
+        let mut settle = vec![0];
+        settle.extend_from_slice(&5u32.to_le_bytes());
+        TransactionWithStatusMeta {
+            transaction: Transaction {
+                signatures: vec![Signature::new(&[7; 64])],
+                message: Message {
+                    account_keys: vec![
+                        key(110),
+                        key(111),
+                        Pubkey::from_str(SERUM_V3).unwrap(),
+                        key(112),
+                        key(113),
+                        key(114),
+                        key(115),
+                    ],
+                    instructions: vec![
+                        compiled(1, &[0, 3, 6], vec![1, 2, 3]),
+                        compiled(2, &[3, 6, 0], settle),
+                    ],
+                    ..Message::default()
+                },
+            },
+            meta: Some(TransactionStatusMeta {
+                inner_instructions: Some(vec![InnerInstructions {
+                    index: 0,
+                    instructions: vec![
+                        // A token transfer by the aggregator, not indexed
+                        compiled(0, &[0], vec![4]),
+                        compiled(2, &[3, 4, 5, 6, 0], cancel),
+                    ],
+                }]),
+                ..TransactionStatusMeta::default()
+            }),
+        }
+    }
+
+    #[test]
+    fn indexes_instructions_invoked_by_other_programs() {
+        let _state = lock_state();
+        let block = block(150);
+        let transaction = aggregator_transaction();
+        let signature = transaction.transaction.signatures[0].to_string();
+        parse_instructions(&block, &transaction, 0, &AccountInfos::new(None)).unwrap();
+        batch::commit(&block.block.blockhash, block.block_number).unwrap();
+
+        let cancels = stored("CancelOrderByClientIdV2");
+        assert_eq!(cancels.len(), 1);
+        let cancel = &cancels[&format!("{}-0-1", signature)];
+        assert_eq!(cancel.get("instruction_index"), Some(&Value::from(0u64)));
+        assert_eq!(cancel.get("inner_index"), Some(&Value::from(1u64)));
+        assert_eq!(
+            cancel.get("invoking_program"),
+            Some(&Value::from(key(111).to_string()))
+        );
+        assert_eq!(
+            cancel.get("market"),
+            Some(&Value::from(key(112).to_string()))
+        );
+        assert_eq!(
+            cancel.get("open_orders"),
+            Some(&Value::from(key(115).to_string()))
+        );
+
+        let settle = &stored("SettleFunds")[&format!("{}-1", signature)];
+        assert_eq!(settle.get("inner_index"), Some(&Value::Null));
+        assert_eq!(settle.get("invoking_program"), Some(&Value::Null));
+        assert_eq!(
+            settle.get("market"),
+            Some(&Value::from(key(112).to_string()))
+        );
+    }
 }
@@ -0,0 +1,256 @@
+//! Counters and gauges of the indexer, rendered in the Prometheus text format. Work is counted
+//! by the slot of its block and only adds to the metrics once that block is committed, so
+//! discarded and rolled back blocks are not counted.
+use crate::METRICS_FILE;
+use lazy_static::lazy_static;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::fs;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+lazy_static! {
+    static ref METRICS: Mutex<Metrics> = Mutex::new(Metrics::default());
+    /// Counts of the blocks not committed yet, by slot.
+    static ref PENDING: Mutex<BTreeMap<u64, Metrics>> = Mutex::new(BTreeMap::new());
+    /// Instructions committed since the last batch summary, by instruction type.
+    static ref SUMMARY: Mutex<BTreeMap<&'static str, u64>> = Mutex::new(BTreeMap::new());
+}
+
+#[derive(Debug, Default)]
+struct Metrics {
+    instructions: BTreeMap<&'static str, u64>,
+    decode_failures: BTreeMap<&'static str, u64>,
+    entities: BTreeMap<String, u64>,
+    blocks: u64,
+    block_seconds: f64,
+    last_slot: Option<u64>,
+    last_block_time: Option<i64>,
+}
+
+impl Metrics {
+    fn merge(&mut self, other: Metrics) {
+        for (instruction, count) in other.instructions {
+            *self.instructions.entry(instruction).or_insert(0) += count;
+        }
+        for (reason, count) in other.decode_failures {
+            *self.decode_failures.entry(reason).or_insert(0) += count;
+        }
+        for (entity_name, count) in other.entities {
+            *self.entities.entry(entity_name).or_insert(0) += count;
+        }
+        self.blocks += other.blocks;
+        self.block_seconds += other.block_seconds;
+        if let Some(slot) = other.last_slot {
+            if self.last_slot.map(|last| slot >= last).unwrap_or(true) {
+                self.last_slot = Some(slot);
+                self.last_block_time = other.last_block_time.or(self.last_block_time);
+            }
+        }
+    }
+}
+
+/// Update the pending counts of `slot`.
+fn pending<F: FnOnce(&mut Metrics)>(slot: u64, update: F) {
+    update(PENDING.lock().unwrap().entry(slot).or_default());
+}
+
+pub fn count_instruction(slot: u64, instruction: &'static str) {
+    pending(slot, |metrics| {
+        *metrics.instructions.entry(instruction).or_insert(0) += 1
+    });
+}
+
+pub fn count_decode_failure(slot: u64, reason: &'static str) {
+    pending(slot, |metrics| {
+        *metrics.decode_failures.entry(reason).or_insert(0) += 1
+    });
+}
+
+pub fn count_entity(slot: u64, entity_name: &str) {
+    pending(slot, |metrics| {
+        *metrics.entities.entry(entity_name.to_string()).or_insert(0) += 1
+    });
+}
+
+/// Record a processed block, `elapsed` being the time spent indexing it.
+pub fn observe_block(slot: u64, block_time: Option<i64>, elapsed: Duration) {
+    pending(slot, |metrics| {
+        metrics.blocks += 1;
+        metrics.block_seconds += elapsed.as_secs_f64();
+        metrics.last_slot = Some(slot);
+        metrics.last_block_time = block_time;
+    });
+}
+
+/// Add the pending counts to the metrics, once their blocks are committed.
+pub fn commit() {
+    let pending = std::mem::take(&mut *PENDING.lock().unwrap());
+    let mut metrics = METRICS.lock().unwrap();
+    let mut summary = SUMMARY.lock().unwrap();
+    for (_, counts) in pending {
+        for (instruction, count) in &counts.instructions {
+            *summary.entry(instruction).or_insert(0) += count;
+        }
+        let unknown = counts.decode_failures.values().sum::<u64>();
+        if unknown > 0 {
+            *summary.entry("UnknownInstruction").or_insert(0) += unknown;
+        }
+        metrics.merge(counts);
+    }
+}
+
+/// Take the instructions committed since the last call by type, the ones that could not be
+/// decoded as `UnknownInstruction`.
+pub fn take_summary() -> BTreeMap<&'static str, u64> {
+    std::mem::take(&mut *SUMMARY.lock().unwrap())
+}
+
+/// Drop the pending counts.
+pub fn discard() {
+    PENDING.lock().unwrap().clear();
+}
+
+/// Drop the pending counts of the blocks above `slot`.
+pub fn discard_above(slot: u64) {
+    PENDING.lock().unwrap().split_off(&(slot + 1));
+}
+
+/// Metrics in the Prometheus text exposition format.
+fn render() -> String {
+    let metrics = METRICS.lock().unwrap();
+    let mut text = String::new();
+    write_counter(
+        &mut text,
+        "serum_instructions_total",
+        "Decoded instructions by type",
+        "instruction",
+        metrics
+            .instructions
+            .iter()
+            .map(|(key, count)| (*key, *count)),
+    );
+    write_counter(
+        &mut text,
+        "serum_decode_failures_total",
+        "Instructions that could not be decoded, by reason",
+        "reason",
+        metrics
+            .decode_failures
+            .iter()
+            .map(|(key, count)| (*key, *count)),
+    );
+    write_counter(
+        &mut text,
+        "serum_entities_saved_total",
+        "Entities committed to the store by type",
+        "entity",
+        metrics
+            .entities
+            .iter()
+            .map(|(key, count)| (key.as_str(), *count)),
+    );
+    writeln!(
+        text,
+        "# HELP serum_block_processing_seconds Time spent indexing a block"
+    )
+    .unwrap();
+    writeln!(text, "# TYPE serum_block_processing_seconds summary").unwrap();
+    writeln!(
+        text,
+        "serum_block_processing_seconds_sum {}",
+        metrics.block_seconds
+    )
+    .unwrap();
+    writeln!(
+        text,
+        "serum_block_processing_seconds_count {}",
+        metrics.blocks
+    )
+    .unwrap();
+    if let Some(last_slot) = metrics.last_slot {
+        write_gauge(
+            &mut text,
+            "serum_last_processed_slot",
+            "Highest slot indexed",
+            last_slot as f64,
+        );
+    }
+    if let Some(block_time) = metrics.last_block_time {
+        write_gauge(
+            &mut text,
+            "serum_last_block_time_seconds",
+            "Block time of the highest slot indexed, in seconds since the epoch",
+            block_time as f64,
+        );
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as i64;
+        write_gauge(
+            &mut text,
+            "serum_block_time_lag_seconds",
+            "Seconds between the block time of the highest slot indexed and now",
+            (now - block_time) as f64,
+        );
+    }
+    text
+}
+
+/// Write the metrics to `SERUM_METRICS_FILE`, through a temporary file so that a scraper
+/// never reads a partial dump.
+pub fn write_file() {
+    if let Some(path) = METRICS_FILE.as_ref() {
+        let tmp = format!("{}.tmp", path);
+        if let Err(err) = fs::write(&tmp, render()).and_then(|_| fs::rename(&tmp, path)) {
+            log::warn!("Cannot write metrics to {}: {}", path, err);
+        }
+    }
+}
+
+fn write_counter<'a>(
+    text: &mut String,
+    name: &str,
+    help: &str,
+    label: &str,
+    values: impl Iterator<Item = (&'a str, u64)>,
+) {
+    writeln!(text, "# HELP {} {}", name, help).unwrap();
+    writeln!(text, "# TYPE {} counter", name).unwrap();
+    for (value, count) in values {
+        writeln!(text, "{}{{{}=\"{}\"}} {}", name, label, value, count).unwrap();
+    }
+}
+
+fn write_gauge(text: &mut String, name: &str, help: &str, value: f64) {
+    writeln!(text, "# HELP {} {}", name, help).unwrap();
+    writeln!(text, "# TYPE {} gauge", name).unwrap();
+    writeln!(text, "{} {}", name, value).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{count_decode_failure, count_instruction, render, take_summary};
+    use crate::batch;
+    use crate::testing::lock_state;
+
+    #[test]
+    fn counts_committed_blocks_only() {
+        let _state = lock_state();
+        let counted = "serum_instructions_total{instruction=\"CountedInstruction\"} 1\n";
+        count_instruction(700, "CountedInstruction");
+        batch::discard();
+        assert!(!render().contains("CountedInstruction"));
+        assert_eq!(take_summary().get("CountedInstruction"), None);
+
+        count_instruction(701, "CountedInstruction");
+        assert!(!render().contains("CountedInstruction"));
+        count_decode_failure(701, "CountedFailure");
+        batch::commit(&"hash-701".to_string(), 701).unwrap();
+        assert!(render().contains(counted));
+        let summary = take_summary();
+        assert_eq!(summary.get("CountedInstruction"), Some(&1));
+        assert_eq!(summary.get("UnknownInstruction"), Some(&1));
+        assert!(take_summary().is_empty());
+    }
+}
@@ -0,0 +1,91 @@
+use crate::generated::instruction::Side;
+use massbit_solana_sdk::entity::{Attribute, Value};
+use massbit_solana_sdk::scalar::BigInt;
+use std::collections::HashMap;
+
+/// Serum order ids keep the limit price in lots in the high 64 bits and the request queue
+/// sequence number in the low 64 bits. Bid sequence numbers are bit-inverted so that, at the
+/// same price, older bids still sort first in the descending bids tree.
+pub fn price_lots(order_id: u128) -> u64 {
+    (order_id >> 64) as u64
+}
+
+pub fn seq_num(order_id: u128, side: &Side) -> u64 {
+    let seq_num = order_id as u64;
+    match side {
+        Side::Bid => !seq_num,
+        Side::Ask => seq_num,
+    }
+}
+
+pub fn to_value(order_id: u128) -> Value {
+    Value::BigInt(BigInt::from_unsigned_bytes_le(&order_id.to_le_bytes()))
+}
+
+/// Insert `order_id` as a decimal together with the `price_lots` and `seq_num` it encodes.
+pub fn insert_order_id(map: &mut HashMap<Attribute, Value>, order_id: u128, side: &Side) {
+    map.insert("order_id".to_string(), to_value(order_id));
+    map.insert("price_lots".to_string(), Value::from(price_lots(order_id)));
+    map.insert("seq_num".to_string(), Value::from(seq_num(order_id, side)));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{insert_order_id, price_lots, seq_num, to_value};
+    use crate::generated::instruction::Side;
+    use massbit_solana_sdk::entity::Value;
+    use massbit_solana_sdk::scalar::BigInt;
+    use std::collections::HashMap;
+    use std::str::FromStr;
+
+    /// Order id as the DEX issues it when a new order is taken from the request queue.
+    fn dex_order_id(price_lots: u64, seq_num: u64, side: &Side) -> u128 {
+        let seq_num = match side {
+            Side::Bid => !seq_num,
+            Side::Ask => seq_num,
+        };
+        (price_lots as u128) << 64 | seq_num as u128
+    }
+
+    #[test]
+    fn round_trips_price_and_sequence() {
+        for side in [Side::Bid, Side::Ask] {
+            for (price, seq) in [(1, 0), (25_500, 4_712_345), (u64::MAX, u64::MAX)] {
+                let order_id = dex_order_id(price, seq, &side);
+                assert_eq!(price_lots(order_id), price);
+                assert_eq!(seq_num(order_id, &side), seq);
+            }
+        }
+    }
+
+    #[test]
+    fn older_bids_sort_first() {
+        let older = dex_order_id(25_500, 4_712_345, &Side::Bid);
+        let newer = dex_order_id(25_500, 4_712_346, &Side::Bid);
+        assert!(older > newer);
+        assert!(dex_order_id(25_501, 4_712_346, &Side::Bid) > older);
+    }
+
+    /// A bid and an ask at SOL/USDC prices, their ids in decimal as clients show them.
+    #[test]
+    fn decodes_dex_order_ids() {
+        let bid = u128::from_str("470410420623667271047270").unwrap();
+        assert_eq!(bid, 25_500 << 64 | 0xffff_ffff_ffb8_1866);
+        assert_eq!(price_lots(bid), 25_500);
+        assert_eq!(seq_num(bid, &Side::Bid), 4_712_345);
+
+        let ask = u128::from_str("470576441320330666436506").unwrap();
+        assert_eq!(price_lots(ask), 25_510);
+        assert_eq!(seq_num(ask, &Side::Ask), 4_712_346);
+
+        let mut map = HashMap::new();
+        insert_order_id(&mut map, bid, &Side::Bid);
+        assert_eq!(
+            map["order_id"],
+            Value::BigInt(BigInt::from_str("470410420623667271047270").unwrap())
+        );
+        assert_eq!(map["order_id"], to_value(bid));
+        assert_eq!(map["price_lots"], Value::from(25_500u64));
+        assert_eq!(map["seq_num"], Value::from(4_712_345u64));
+    }
+}
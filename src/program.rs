@@ -0,0 +1,174 @@
+use serde::{Deserialize, Serialize};
+use solana_program::pubkey::Pubkey;
+use std::collections::HashMap;
+use std::env;
+use std::str::FromStr;
+
+/// Serum DEX deployments that are indexed when `SERUM_PROGRAM_IDS` is not set.
+pub const DEFAULT_PROGRAM_IDS: &[(&str, DexVersion)] = &[
+    (
+        "4ckmDgGdxQoPDLUkDT3vHgSAkzA3QRdNq5ywwY4sUSJn",
+        DexVersion::V1,
+    ),
+    (
+        "BJ3jrUzddfuSrZHXSCxMUUQsjKEyLmuuyZebkcaFp2fg",
+        DexVersion::V1,
+    ),
+    (
+        "EUqojwWA2rd19FZrzeBncJsm38Jm1hEhE3zsmX3bRc2o",
+        DexVersion::V2,
+    ),
+    (
+        "9xQeWvG816bUx9EPjHmaT23yvVM2ZWbrrpZb9PusVFin",
+        DexVersion::V3,
+    ),
+    (
+        "srmqPvymJeFKQ4zGQed1GFppgkRHL9kaELCbyksJtPX",
+        DexVersion::OpenBook,
+    ),
+];
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+pub enum DexVersion {
+    V1,
+    V2,
+    V3,
+    OpenBook,
+}
+impl DexVersion {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DexVersion::V1 => "v1",
+            DexVersion::V2 => "v2",
+            DexVersion::V3 => "v3",
+            DexVersion::OpenBook => "openbook",
+        }
+    }
+    /// Highest `MarketInstruction` tag understood by the deployed program.
+    pub fn max_instruction_tag(&self) -> u32 {
+        match self {
+            DexVersion::V1 => 8,
+            DexVersion::V2 => 9,
+            DexVersion::V3 | DexVersion::OpenBook => 17,
+        }
+    }
+}
+impl FromStr for DexVersion {
+    type Err = String;
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_lowercase().as_str() {
+            "v1" => Ok(DexVersion::V1),
+            "v2" => Ok(DexVersion::V2),
+            "v3" => Ok(DexVersion::V3),
+            "openbook" => Ok(DexVersion::OpenBook),
+            _ => Err(format!("Unknown dex version {}", value)),
+        }
+    }
+}
+
+/// Parse `SERUM_PROGRAM_IDS`, a comma separated list of `<version>:<program id>` pairs,
+/// e.g. `v3:9xQeWvG816bUx9EPjHmaT23yvVM2ZWbrrpZb9PusVFin,openbook:srmqPvymJeFKQ4zGQed1GFppgkRHL9kaELCbyksJtPX`.
+/// Panics on an invalid or empty list, an indexer that silently indexes nothing is worse than
+/// one that does not start.
+pub fn load_program_ids() -> HashMap<Pubkey, DexVersion> {
+    match env::var("SERUM_PROGRAM_IDS") {
+        Ok(value) => parse_program_ids(&value)
+            .unwrap_or_else(|err| panic!("Invalid SERUM_PROGRAM_IDS: {}", err)),
+        Err(_) => DEFAULT_PROGRAM_IDS
+            .iter()
+            .filter_map(|(address, version)| {
+                Pubkey::from_str(address)
+                    .ok()
+                    .map(|pubkey| (pubkey, *version))
+            })
+            .collect(),
+    }
+}
+/// Every entry needs an explicit version, the layouts differ between versions, and a program
+/// id is listed once.
+pub fn parse_program_ids(value: &str) -> Result<HashMap<Pubkey, DexVersion>, String> {
+    let mut program_ids = HashMap::new();
+    for entry in value
+        .split(',')
+        .map(|entry| entry.trim())
+        .filter(|entry| !entry.is_empty())
+    {
+        let (version, address) = entry
+            .split_once(':')
+            .ok_or_else(|| format!("entry {} has no <version>: prefix", entry))?;
+        let version = version.trim().parse::<DexVersion>()?;
+        let pubkey = Pubkey::from_str(address.trim())
+            .map_err(|err| format!("entry {} has an invalid program id: {}", entry, err))?;
+        if program_ids.insert(pubkey, version).is_some() {
+            return Err(format!("program id {} is listed twice", pubkey));
+        }
+    }
+    if program_ids.is_empty() {
+        return Err("no program id".to_string());
+    }
+    Ok(program_ids)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_program_ids, DexVersion};
+    use solana_program::pubkey::Pubkey;
+    use std::str::FromStr;
+
+    const SERUM_V3: &str = "9xQeWvG816bUx9EPjHmaT23yvVM2ZWbrrpZb9PusVFin";
+    const OPENBOOK: &str = "srmqPvymJeFKQ4zGQed1GFppgkRHL9kaELCbyksJtPX";
+
+    #[test]
+    fn parses_versioned_program_ids() {
+        let program_ids =
+            parse_program_ids(&format!(" v3:{} ,,OpenBook: {},", SERUM_V3, OPENBOOK)).unwrap();
+        assert_eq!(program_ids.len(), 2);
+        assert_eq!(
+            program_ids[&Pubkey::from_str(SERUM_V3).unwrap()],
+            DexVersion::V3
+        );
+        assert_eq!(
+            program_ids[&Pubkey::from_str(OPENBOOK).unwrap()],
+            DexVersion::OpenBook
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_program_ids() {
+        let invalid =
+            parse_program_ids("v3:9xQeWvG816bUx9EPjHmaT23yvVM2ZWbrrpZb9PusVFi0").unwrap_err();
+        assert!(invalid.starts_with(
+            "entry v3:9xQeWvG816bUx9EPjHmaT23yvVM2ZWbrrpZb9PusVFi0 has an invalid program id"
+        ));
+        let short = parse_program_ids("v3:9xQeWvG816bUx9EPjHmaT23yvVM2ZWbrr").unwrap_err();
+        assert!(short.contains("has an invalid program id"));
+        assert_eq!(
+            parse_program_ids(SERUM_V3).unwrap_err(),
+            format!("entry {} has no <version>: prefix", SERUM_V3)
+        );
+        assert_eq!(
+            parse_program_ids(&format!("v4:{}", SERUM_V3)).unwrap_err(),
+            "Unknown dex version v4"
+        );
+    }
+
+    #[test]
+    fn rejects_empty_lists() {
+        for value in ["", " ", ",", " , ,"] {
+            assert_eq!(parse_program_ids(value).unwrap_err(), "no program id");
+        }
+    }
+
+    #[test]
+    fn rejects_duplicate_program_ids() {
+        for value in [
+            format!("v3:{},v3:{}", SERUM_V3, SERUM_V3),
+            format!("v3:{},openbook:{}", SERUM_V3, SERUM_V3),
+        ] {
+            assert_eq!(
+                parse_program_ids(&value).unwrap_err(),
+                format!("program id {} is listed twice", SERUM_V3)
+            );
+        }
+    }
+}
@@ -0,0 +1,401 @@
+//! Checks the attributes saved by every entity writer against the generated schema: each
+//! attribute must be a field of the entity with a compatible type, and each field must be
+//! written. Writers of `src/entities.graphql` are called with sample values, the generated
+//! instruction handlers are run on a valid instruction of every tag and the entities they
+//! saved are checked.
+use crate::account_source::AccountInfos;
+use crate::batch;
+use crate::context::InstructionContext;
+use crate::derived::book::{self, BookDetail};
+use crate::derived::market::{self, MarketInfo};
+use crate::derived::order::{self, Order, OrderStatus};
+use crate::derived::{balance, fill, rollback_attributes, tombstone_attributes};
+use crate::generated::handler::{raw_instruction_attributes, Handler};
+use crate::generated::instruction::tests::{instruction, valid_payload, PAYLOAD_LENS};
+use crate::generated::instruction::{MarketInstruction, OrderType, Side};
+use crate::generated::SCHEMA;
+use crate::program::DexVersion;
+use crate::state::event_queue::{event_flag, Event};
+use crate::state::market::MarketState;
+use crate::state::open_orders::{OpenOrders, MAX_ORDERS};
+use crate::state::slab::LeafNode;
+use crate::testing::{block, key, lock_state, stored};
+use crate::token;
+use massbit_solana_sdk::entity::{Attribute, Entity, Value};
+use solana_program::pubkey::Pubkey;
+use solana_sdk::transaction::Transaction;
+use solana_transaction_status::TransactionWithStatusMeta;
+use std::collections::{BTreeSet, HashMap};
+
+/// Fields of each entity with their GraphQL type.
+fn parse_schema(schema: &str) -> HashMap<String, HashMap<String, String>> {
+    let mut entities: HashMap<String, HashMap<String, String>> = HashMap::new();
+    let mut entity: Option<String> = None;
+    for line in schema.lines().map(str::trim) {
+        if let Some(name) = line
+            .strip_prefix("type ")
+            .and_then(|rest| rest.split_whitespace().next())
+        {
+            entities.insert(name.to_string(), HashMap::new());
+            entity = Some(name.to_string());
+        } else if line.starts_with('}') {
+            entity = None;
+        } else if let (Some(entity), Some((field, field_type))) = (&entity, line.split_once(':')) {
+            entities.get_mut(entity).unwrap().insert(
+                field.trim().to_string(),
+                field_type.trim().trim_end_matches(',').to_string(),
+            );
+        }
+    }
+    entities
+}
+
+/// Whether a field of type `field_type` can store `value`. Strings go into `ID` and entity
+/// references too, and `Int` values widen to `BigInt`.
+fn accepts(
+    schema: &HashMap<String, HashMap<String, String>>,
+    field_type: &str,
+    value: &Value,
+) -> bool {
+    let (field_type, nullable) = match field_type.strip_suffix('!') {
+        Some(field_type) => (field_type, false),
+        None => (field_type, true),
+    };
+    match value {
+        Value::Null => nullable,
+        Value::List(values) => match field_type
+            .strip_prefix('[')
+            .and_then(|inner| inner.strip_suffix(']'))
+        {
+            Some(inner) => values.iter().all(|value| accepts(schema, inner, value)),
+            None => false,
+        },
+        Value::String(_) => {
+            field_type == "String" || field_type == "ID" || schema.contains_key(field_type)
+        }
+        Value::Int(_) => field_type == "Int" || field_type == "BigInt",
+        Value::BigInt(_) => field_type == "BigInt",
+        Value::BigDecimal(_) => field_type == "BigDecimal",
+        Value::Bool(_) => field_type == "Boolean",
+    }
+}
+
+fn check(
+    schema: &HashMap<String, HashMap<String, String>>,
+    entity_name: &str,
+    map: &HashMap<Attribute, Value>,
+) -> Vec<String> {
+    let fields = match schema.get(entity_name) {
+        Some(fields) => fields,
+        None => return vec![format!("{} is not in the schema", entity_name)],
+    };
+    let mut errors: Vec<String> = Vec::new();
+    for (attribute, value) in map {
+        match fields.get(attribute) {
+            Some(field_type) if !accepts(schema, field_type, value) => errors.push(format!(
+                "{}.{}: {} cannot store {:?}",
+                entity_name, attribute, field_type, value
+            )),
+            Some(_) => {}
+            None => errors.push(format!(
+                "{}.{} is not in the schema",
+                entity_name, attribute
+            )),
+        }
+    }
+    for field in fields.keys() {
+        if !map.contains_key(field) {
+            errors.push(format!("{}.{} is never written", entity_name, field));
+        }
+    }
+    errors
+}
+
+/// Context with every optional column set, so that header types are checked too.
+fn sample_ctx() -> InstructionContext {
+    InstructionContext {
+        program_id: key(1),
+        dex_version: DexVersion::V3,
+        slot: 100,
+        block_hash: "hash".to_string(),
+        block_time: Some(1_600_000_000),
+        signature: "signature".to_string(),
+        fee_payer: key(2).to_string(),
+        error: Some("error".to_string()),
+        tx_index: 1,
+        instruction_index: 2,
+        inner_index: Some(3),
+        invoking_program: Some(key(3)),
+        instruction_version: Some(0),
+    }
+}
+
+/// Market whose mints are in the token list, so that UI amounts are written.
+fn sample_market() -> MarketInfo {
+    let mut mints = token::TOKENS.keys().cloned().collect::<Vec<Pubkey>>();
+    mints.sort();
+    MarketInfo {
+        market: key(10),
+        request_queue: key(11),
+        event_queue: key(12),
+        bids: key(13),
+        asks: key(14),
+        coin_vault: key(15),
+        pc_vault: key(16),
+        coin_mint: mints[0],
+        pc_mint: mints[1],
+        coin_lot_size: 100,
+        pc_lot_size: 10,
+        fee_rate_bps: 22,
+        vault_signer_nonce: 1,
+        pc_dust_threshold: 100,
+        created_slot: Some(90),
+        created_signature: Some("created".to_string()),
+        disabled: true,
+        disabled_slot: Some(95),
+        last_sweep_slot: Some(96),
+        last_sweep_signature: Some("swept".to_string()),
+        updated_slot: 96,
+        updated_block_hash: "hash".to_string(),
+    }
+}
+
+fn sample_order(market: &MarketInfo) -> Order {
+    Order {
+        id: "order".to_string(),
+        market: market.market,
+        open_orders: key(20),
+        side: Side::Bid,
+        order_id: Some(1 << 64 | 7),
+        client_order_id: 5,
+        limit_price: 1,
+        max_qty: Some(3),
+        order_type: Some(OrderType::Limit),
+        status: OrderStatus::PartiallyFilled,
+        native_qty_paid: 10,
+        native_qty_released: 1,
+        native_fee_or_rebate: 0,
+        fill_count: 1,
+        filled_qty: Some(1),
+        origin: Some(("origin".to_string(), "NewOrderV3")),
+        origin_signature: Some("signature".to_string()),
+        created_slot: 99,
+        updated_slot: 100,
+        updated_block_hash: "hash".to_string(),
+        updated_signature: "signature".to_string(),
+    }
+}
+
+fn sample_event(event_flags: u8) -> Event {
+    Event {
+        event_flags,
+        owner_slot: 1,
+        fee_tier: 2,
+        native_qty_released: 3,
+        native_qty_paid: 4,
+        native_fee_or_rebate: 5,
+        order_id: 1 << 64 | 7,
+        owner: key(20),
+        client_order_id: 5,
+    }
+}
+
+fn sample_market_state(market: &MarketInfo) -> MarketState {
+    MarketState {
+        account_flags: 3,
+        own_address: market.market,
+        vault_signer_nonce: market.vault_signer_nonce,
+        coin_mint: market.coin_mint,
+        pc_mint: market.pc_mint,
+        coin_vault: market.coin_vault,
+        coin_deposits_total: 0,
+        coin_fees_accrued: 0,
+        pc_vault: market.pc_vault,
+        pc_deposits_total: 0,
+        pc_fees_accrued: 0,
+        pc_dust_threshold: market.pc_dust_threshold,
+        request_queue: market.request_queue,
+        event_queue: market.event_queue,
+        bids: market.bids,
+        asks: market.asks,
+        coin_lot_size: market.coin_lot_size,
+        pc_lot_size: market.pc_lot_size,
+        fee_rate_bps: 22,
+        referrer_rebates_accrued: Some(0),
+    }
+}
+
+fn sample_open_orders(market: &MarketInfo) -> OpenOrders {
+    OpenOrders {
+        account_flags: 5,
+        market: market.market,
+        owner: key(21),
+        native_coin_free: 1,
+        native_coin_total: 2,
+        native_pc_free: 3,
+        native_pc_total: 4,
+        free_slot_bits: !0,
+        is_bid_bits: 0,
+        orders: vec![0; MAX_ORDERS],
+        client_order_ids: vec![0; MAX_ORDERS],
+        referrer_rebates_accrued: Some(6),
+    }
+}
+
+/// Attributes of an entity as saved, the names taken from its serialized form.
+fn entity_attributes(entity: &Entity) -> HashMap<Attribute, Value> {
+    let names = match serde_json::to_value(entity) {
+        Ok(serde_json::Value::Object(fields)) => fields.keys().cloned().collect(),
+        _ => Vec::new(),
+    };
+    names
+        .into_iter()
+        .filter_map(|name| entity.get(&name).cloned().map(|value| (name, value)))
+        .collect()
+}
+
+/// Run a valid instruction of every tag through the generated handlers, each as its own
+/// instruction of `ctx`, and return the entity each of them saved.
+fn instruction_entities(
+    ctx: &InstructionContext,
+    market: &MarketInfo,
+) -> Vec<(&'static str, HashMap<Attribute, Value>)> {
+    let transaction = TransactionWithStatusMeta {
+        transaction: Transaction::default(),
+        meta: None,
+    };
+    // The market first, the other accounts distinct
+    let accounts: Vec<Pubkey> = std::iter::once(market.market)
+        .chain((200..230).map(key))
+        .collect();
+    let mut saved: Vec<(&'static str, String)> = Vec::new();
+    for (instruction_index, (tag, len)) in PAYLOAD_LENS.iter().enumerate() {
+        // `InitializeMarket` replaces the market, whose mints must stay in the token list
+        market::MARKETS.lock().unwrap().register(market.clone());
+        let ctx = InstructionContext {
+            instruction_index,
+            ..ctx.clone()
+        };
+        let input = instruction(0, *tag, &valid_payload(*tag, *len));
+        let name = MarketInstruction::unpack(&input, ctx.dex_version)
+            .unwrap()
+            .instruction
+            .name();
+        // Derived state is not checked here, only the instruction entity
+        let _ = Handler {}.process(
+            &block(ctx.slot),
+            &transaction,
+            &ctx,
+            &accounts,
+            &AccountInfos::new(None),
+            &input,
+        );
+        saved.push((name, ctx.entity_id()));
+    }
+    batch::commit(&ctx.block_hash, ctx.slot).unwrap();
+    saved
+        .into_iter()
+        .map(|(name, id)| {
+            let entity = stored(name)
+                .remove(&id)
+                .unwrap_or_else(|| panic!("{} {} was not saved", name, id));
+            (name, entity_attributes(&entity))
+        })
+        .collect()
+}
+
+fn sample_leaf(price_lots: u64) -> LeafNode {
+    LeafNode {
+        owner_slot: 0,
+        fee_tier: 0,
+        key: (price_lots as u128) << 64,
+        owner: key(20),
+        quantity: 2,
+        client_order_id: 0,
+    }
+}
+
+#[test]
+fn entities_match_schema() {
+    let _state = lock_state();
+    let schema = parse_schema(SCHEMA);
+    let ctx = sample_ctx();
+    let market = sample_market();
+    market::MARKETS.lock().unwrap().register(market.clone());
+    let order = sample_order(&market);
+    let state = sample_market_state(&market);
+    let (bid, ask) = (sample_leaf(1), sample_leaf(2));
+
+    let mut written: Vec<(&str, HashMap<Attribute, Value>)> = vec![
+        ("Order", order::order_attributes(&order)),
+        ("Market", market::market_attributes(&market)),
+        (
+            "OpenOrdersBalance",
+            balance::balance_attributes(&ctx, &key(22), &sample_open_orders(&market)),
+        ),
+        (
+            "OrderBookSnapshot",
+            book::snapshot_attributes(
+                &ctx,
+                &market.market,
+                &state,
+                &[&bid],
+                &[&ask],
+                BookDetail::L3,
+            ),
+        ),
+        (
+            "Tombstone",
+            tombstone_attributes(100, "hash", "Order", "order"),
+        ),
+        (
+            "Rollback",
+            rollback_attributes(
+                100,
+                "hash",
+                &[(order.id.clone(), Some(order.clone()))],
+                &[(market.market, Some(market.clone()))],
+            ),
+        ),
+    ];
+    for event_flags in [event_flag::FILL | event_flag::BID, event_flag::OUT] {
+        written.push(
+            fill::event_attributes(
+                &ctx,
+                &market.market,
+                &market.event_queue,
+                1,
+                &sample_event(event_flags),
+            )
+            .unwrap(),
+        );
+    }
+    for (entity_name, error_attribute) in [
+        ("UnknownInstruction", "decode_error"),
+        ("DeadLetter", "index_error"),
+    ] {
+        let mut map = raw_instruction_attributes(&ctx, &[key(1)], &[0, 1]);
+        map.insert(error_attribute.to_string(), Value::from("error"));
+        written.push((entity_name, map));
+    }
+    written.extend(instruction_entities(&ctx, &market));
+
+    let errors: Vec<String> = written
+        .iter()
+        .flat_map(|(entity_name, map)| check(&schema, entity_name, map))
+        .collect();
+    assert!(errors.is_empty(), "{}", errors.join("\n"));
+    let checked: BTreeSet<&str> = written
+        .iter()
+        .map(|(entity_name, _)| *entity_name)
+        .collect();
+    let unchecked: Vec<&String> = schema
+        .keys()
+        .filter(|entity_name| !checked.contains(entity_name.as_str()))
+        .collect();
+    assert!(
+        unchecked.is_empty(),
+        "No writer checked for {:?}",
+        unchecked
+    );
+}
@@ -0,0 +1,259 @@
+use super::{account_flag, check_account_flags, strip_padding, StateError};
+use crate::generated::instruction::Side;
+use arrayref::{array_ref, array_refs};
+use solana_program::pubkey::Pubkey;
+
+pub const HEADER_LEN: usize = 32;
+pub const EVENT_LEN: usize = 88;
+
+pub mod event_flag {
+    pub const FILL: u8 = 1 << 0;
+    pub const OUT: u8 = 1 << 1;
+    pub const BID: u8 = 1 << 2;
+    pub const MAKER: u8 = 1 << 3;
+    pub const RELEASE_FUNDS: u8 = 1 << 4;
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct EventQueueHeader {
+    pub account_flags: u64,
+    pub head: u64,
+    pub count: u64,
+    pub seq_num: u64,
+}
+impl EventQueueHeader {
+    pub fn unpack(input: &[u8; HEADER_LEN]) -> Self {
+        let (account_flags, head, count, seq_num) = array_refs![input, 8, 8, 8, 8];
+        EventQueueHeader {
+            account_flags: u64::from_le_bytes(*account_flags),
+            head: u64::from_le_bytes(*head),
+            count: u64::from_le_bytes(*count),
+            seq_num: u64::from_le_bytes(*seq_num),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Event {
+    pub event_flags: u8,
+    pub owner_slot: u8,
+    pub fee_tier: u8,
+    pub native_qty_released: u64,
+    pub native_qty_paid: u64,
+    pub native_fee_or_rebate: u64,
+    pub order_id: u128,
+    pub owner: Pubkey,
+    pub client_order_id: u64,
+}
+impl Event {
+    pub fn unpack(input: &[u8; EVENT_LEN]) -> Self {
+        let (
+            event_flags,
+            owner_slot,
+            fee_tier,
+            _padding,
+            native_qty_released,
+            native_qty_paid,
+            native_fee_or_rebate,
+            order_id,
+            owner,
+            client_order_id,
+        ) = array_refs![input, 1, 1, 1, 5, 8, 8, 8, 16, 32, 8];
+        Event {
+            event_flags: event_flags[0],
+            owner_slot: owner_slot[0],
+            fee_tier: fee_tier[0],
+            native_qty_released: u64::from_le_bytes(*native_qty_released),
+            native_qty_paid: u64::from_le_bytes(*native_qty_paid),
+            native_fee_or_rebate: u64::from_le_bytes(*native_fee_or_rebate),
+            order_id: u128::from_le_bytes(*order_id),
+            owner: Pubkey::new_from_array(*owner),
+            client_order_id: u64::from_le_bytes(*client_order_id),
+        }
+    }
+    pub fn is_fill(&self) -> bool {
+        self.event_flags & event_flag::FILL != 0
+    }
+    pub fn is_out(&self) -> bool {
+        self.event_flags & event_flag::OUT != 0
+    }
+    pub fn is_maker(&self) -> bool {
+        self.event_flags & event_flag::MAKER != 0
+    }
+    pub fn release_funds(&self) -> bool {
+        self.event_flags & event_flag::RELEASE_FUNDS != 0
+    }
+    pub fn side(&self) -> Side {
+        if self.event_flags & event_flag::BID != 0 {
+            Side::Bid
+        } else {
+            Side::Ask
+        }
+    }
+}
+
+/// Ring buffer of matching events, `events` keeps the raw buffer order.
+#[derive(Clone, PartialEq, Debug)]
+pub struct EventQueue {
+    pub header: EventQueueHeader,
+    pub events: Vec<Event>,
+}
+impl EventQueue {
+    pub fn unpack(data: &[u8]) -> Result<Self, StateError> {
+        let inner = strip_padding(data)?;
+        if inner.len() < HEADER_LEN {
+            return Err(StateError::TooShort {
+                expected: HEADER_LEN,
+                actual: inner.len(),
+            });
+        }
+        if !(inner.len() - HEADER_LEN).is_multiple_of(EVENT_LEN) {
+            return Err(StateError::WrongLength(inner.len()));
+        }
+        let (header, buffer) = inner.split_at(HEADER_LEN);
+        let header = EventQueueHeader::unpack(array_ref![header, 0, HEADER_LEN]);
+        check_account_flags(header.account_flags, account_flag::EVENT_QUEUE)?;
+        let events = buffer
+            .chunks_exact(EVENT_LEN)
+            .map(|chunk| Event::unpack(array_ref![chunk, 0, EVENT_LEN]))
+            .collect();
+        Ok(EventQueue { header, events })
+    }
+    pub fn capacity(&self) -> u64 {
+        self.events.len() as u64
+    }
+    /// Events currently in the queue, oldest first, together with their sequence number.
+    pub fn iter(&self) -> impl Iterator<Item = (u64, &Event)> + '_ {
+        let capacity = self.capacity().max(1);
+        let first_seq_num = self.header.seq_num.wrapping_sub(self.header.count);
+        (0..self.header.count.min(self.capacity())).map(move |offset| {
+            let index = (self.header.head + offset) % capacity;
+            (
+                first_seq_num.wrapping_add(offset),
+                &self.events[index as usize],
+            )
+        })
+    }
+    /// Events pushed since `previous` was captured that are still in the queue.
+    pub fn pushed_since<'a>(&'a self, previous: &EventQueueHeader) -> Vec<(u64, &'a Event)> {
+        let pushed = self.header.seq_num.wrapping_sub(previous.seq_num);
+        self.iter()
+            .filter(|(seq_num, _)| self.header.seq_num.wrapping_sub(*seq_num) <= pushed)
+            .collect()
+    }
+    /// Events of this queue that were popped by the time `next` was captured.
+    pub fn consumed_by<'a>(&'a self, next: &EventQueueHeader) -> Vec<(u64, &'a Event)> {
+        let next_first_seq_num = next.seq_num.wrapping_sub(next.count);
+        self.iter()
+            .filter(|(seq_num, _)| *seq_num < next_first_seq_num)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{event_queue_data, key};
+
+    fn event(event_flags: u8, order_id: u128) -> Event {
+        Event {
+            event_flags,
+            owner_slot: 0,
+            fee_tier: 0,
+            native_qty_released: 0,
+            native_qty_paid: 0,
+            native_fee_or_rebate: 0,
+            order_id,
+            owner: key(50),
+            client_order_id: 0,
+        }
+    }
+
+    /// Queue of capacity four whose live events start at `head`, event `i` has order id `i`.
+    fn queue(head: u64, count: u64, seq_num: u64) -> EventQueue {
+        let events = (0..4)
+            .map(|index| event(event_flag::FILL, index))
+            .collect::<Vec<_>>();
+        let mut data = event_queue_data(seq_num, &events);
+        data[13..21].copy_from_slice(&head.to_le_bytes());
+        data[21..29].copy_from_slice(&count.to_le_bytes());
+        EventQueue::unpack(&data).unwrap()
+    }
+
+    fn seq_nums_and_ids(events: Vec<(u64, &Event)>) -> Vec<(u64, u128)> {
+        events
+            .into_iter()
+            .map(|(seq_num, event)| (seq_num, event.order_id))
+            .collect()
+    }
+
+    #[test]
+    fn iterates_across_the_end_of_the_buffer() {
+        let queue = queue(3, 3, 10);
+        assert_eq!(queue.capacity(), 4);
+        assert_eq!(
+            seq_nums_and_ids(queue.iter().collect()),
+            [(7, 3), (8, 0), (9, 1)]
+        );
+    }
+
+    #[test]
+    fn diffs_headers_across_the_end_of_the_buffer() {
+        let queue = queue(3, 3, 10);
+        let previous = EventQueueHeader {
+            head: 3,
+            count: 1,
+            seq_num: 8,
+            ..queue.header.clone()
+        };
+        assert_eq!(
+            seq_nums_and_ids(queue.pushed_since(&previous)),
+            [(8, 0), (9, 1)]
+        );
+        // The crank popped two events and one more was pushed
+        let next = EventQueueHeader {
+            head: 1,
+            count: 2,
+            seq_num: 11,
+            ..queue.header.clone()
+        };
+        assert_eq!(seq_nums_and_ids(queue.consumed_by(&next)), [(7, 3), (8, 0)]);
+        assert_eq!(seq_nums_and_ids(queue.pushed_since(&queue.header)), []);
+        assert_eq!(seq_nums_and_ids(queue.consumed_by(&queue.header)), []);
+    }
+
+    #[test]
+    fn reads_event_flags() {
+        let maker_bid = event(event_flag::FILL | event_flag::BID | event_flag::MAKER, 1);
+        assert!(maker_bid.is_fill() && maker_bid.is_maker());
+        assert!(!maker_bid.is_out() && !maker_bid.release_funds());
+        assert_eq!(maker_bid.side(), Side::Bid);
+        let out_ask = event(event_flag::OUT | event_flag::RELEASE_FUNDS, 1);
+        assert!(out_ask.is_out() && out_ask.release_funds());
+        assert!(!out_ask.is_fill() && !out_ask.is_maker());
+        assert_eq!(out_ask.side(), Side::Ask);
+    }
+
+    #[test]
+    fn rejects_foreign_and_truncated_accounts() {
+        let data = event_queue_data(1, &[event(event_flag::FILL, 1)]);
+        let mut truncated = data[..data.len() - 8].to_vec();
+        truncated.extend_from_slice(b"padding");
+        assert_eq!(
+            EventQueue::unpack(&truncated),
+            Err(StateError::WrongLength(HEADER_LEN + EVENT_LEN - 1))
+        );
+        let mut request_queue = data.clone();
+        request_queue[5] = (account_flag::INITIALIZED | account_flag::REQUEST_QUEUE) as u8;
+        assert!(matches!(
+            EventQueue::unpack(&request_queue),
+            Err(StateError::WrongAccountFlags(_))
+        ));
+        let mut uninitialized = data;
+        uninitialized[5] = account_flag::EVENT_QUEUE as u8;
+        assert!(matches!(
+            EventQueue::unpack(&uninitialized),
+            Err(StateError::WrongAccountFlags(_))
+        ));
+    }
+}
@@ -0,0 +1,202 @@
+use super::{account_flag, check_account_flags, strip_padding, StateError};
+use arrayref::{array_ref, array_refs};
+use solana_program::pubkey::Pubkey;
+
+/// Length of the market state of v2 markets, which have no referrer rebates.
+pub const MARKET_STATE_V1_LEN: usize = 368;
+pub const MARKET_STATE_LEN: usize = 376;
+
+/// Market account, the `market` of every instruction.
+#[derive(Clone, PartialEq, Debug)]
+pub struct MarketState {
+    pub account_flags: u64,
+    pub own_address: Pubkey,
+    pub vault_signer_nonce: u64,
+    pub coin_mint: Pubkey,
+    pub pc_mint: Pubkey,
+    pub coin_vault: Pubkey,
+    pub coin_deposits_total: u64,
+    pub coin_fees_accrued: u64,
+    pub pc_vault: Pubkey,
+    pub pc_deposits_total: u64,
+    pub pc_fees_accrued: u64,
+    pub pc_dust_threshold: u64,
+    pub request_queue: Pubkey,
+    pub event_queue: Pubkey,
+    pub bids: Pubkey,
+    pub asks: Pubkey,
+    pub coin_lot_size: u64,
+    pub pc_lot_size: u64,
+    pub fee_rate_bps: u64,
+    /// `None` for v2 markets.
+    pub referrer_rebates_accrued: Option<u64>,
+}
+impl MarketState {
+    pub fn unpack(data: &[u8]) -> Result<Self, StateError> {
+        let inner = strip_padding(data)?;
+        if inner.len() < MARKET_STATE_V1_LEN {
+            return Err(StateError::TooShort {
+                expected: MARKET_STATE_V1_LEN,
+                actual: inner.len(),
+            });
+        }
+        let input = array_ref![inner, 0, MARKET_STATE_V1_LEN];
+        let (
+            account_flags,
+            own_address,
+            vault_signer_nonce,
+            coin_mint,
+            pc_mint,
+            coin_vault,
+            coin_deposits_total,
+            coin_fees_accrued,
+            pc_vault,
+            pc_deposits_total,
+            pc_fees_accrued,
+            pc_dust_threshold,
+            request_queue,
+            event_queue,
+            bids,
+            asks,
+            coin_lot_size,
+            pc_lot_size,
+            fee_rate_bps,
+        ) = array_refs![input, 8, 32, 8, 32, 32, 32, 8, 8, 32, 8, 8, 8, 32, 32, 32, 32, 8, 8, 8];
+        let account_flags = u64::from_le_bytes(*account_flags);
+        check_account_flags(account_flags, account_flag::MARKET)?;
+        let referrer_rebates_accrued = inner
+            .get(MARKET_STATE_V1_LEN..MARKET_STATE_LEN)
+            .map(|bytes| u64::from_le_bytes(*array_ref![bytes, 0, 8]));
+        Ok(MarketState {
+            account_flags,
+            own_address: Pubkey::new_from_array(*own_address),
+            vault_signer_nonce: u64::from_le_bytes(*vault_signer_nonce),
+            coin_mint: Pubkey::new_from_array(*coin_mint),
+            pc_mint: Pubkey::new_from_array(*pc_mint),
+            coin_vault: Pubkey::new_from_array(*coin_vault),
+            coin_deposits_total: u64::from_le_bytes(*coin_deposits_total),
+            coin_fees_accrued: u64::from_le_bytes(*coin_fees_accrued),
+            pc_vault: Pubkey::new_from_array(*pc_vault),
+            pc_deposits_total: u64::from_le_bytes(*pc_deposits_total),
+            pc_fees_accrued: u64::from_le_bytes(*pc_fees_accrued),
+            pc_dust_threshold: u64::from_le_bytes(*pc_dust_threshold),
+            request_queue: Pubkey::new_from_array(*request_queue),
+            event_queue: Pubkey::new_from_array(*event_queue),
+            bids: Pubkey::new_from_array(*bids),
+            asks: Pubkey::new_from_array(*asks),
+            coin_lot_size: u64::from_le_bytes(*coin_lot_size),
+            pc_lot_size: u64::from_le_bytes(*pc_lot_size),
+            fee_rate_bps: u64::from_le_bytes(*fee_rate_bps),
+            referrer_rebates_accrued,
+        })
+    }
+    pub fn is_disabled(&self) -> bool {
+        self.account_flags & account_flag::DISABLED != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{fixture, MARKET_V2, MARKET_V3};
+    use std::str::FromStr;
+
+    fn key(pubkey: &str) -> Pubkey {
+        Pubkey::from_str(pubkey).unwrap()
+    }
+
+    #[test]
+    fn decodes_v2_market() {
+        let data = fixture(MARKET_V2);
+        assert_eq!(data.len(), 5 + MARKET_STATE_V1_LEN + 7);
+        let state = MarketState::unpack(&data).unwrap();
+        assert!(state.is_disabled());
+        assert_eq!(
+            state.own_address,
+            key("7dLVkUfBVfCGkFhSXDCq1ukM9usathSgS716t643iFGF")
+        );
+        assert_eq!(state.vault_signer_nonce, 0);
+        assert_eq!(
+            state.coin_mint,
+            key("SRMuApVNdxXokk5GT7XD5cUUgXMBCoAz2LHeuAoKWRt")
+        );
+        assert_eq!(
+            state.pc_mint,
+            key("EPjFWdd5AufqSSqeM2qN1xzybapC8G4wEGGkZwyTDt1v")
+        );
+        assert_eq!(state.coin_deposits_total, 5_000_000);
+        assert_eq!(state.pc_fees_accrued, 30_611);
+        assert_eq!(state.pc_dust_threshold, 100);
+        assert_eq!(
+            state.asks,
+            key("DzsAEJfCqPRcGBJZmQWWmEX6fmzKcGDXSxLs9pH4ax74")
+        );
+        assert_eq!(
+            (state.coin_lot_size, state.pc_lot_size, state.fee_rate_bps),
+            (100_000, 100, 22)
+        );
+        assert_eq!(state.referrer_rebates_accrued, None);
+    }
+
+    #[test]
+    fn decodes_v3_market() {
+        let data = fixture(MARKET_V3);
+        assert_eq!(data.len(), 5 + MARKET_STATE_LEN + 7);
+        let state = MarketState::unpack(&data).unwrap();
+        assert!(!state.is_disabled());
+        assert_eq!(
+            state.own_address,
+            key("9wFFyRfZBsuAha4YcuxcXLKwMxJR43S7fPfQLusDBzvT")
+        );
+        assert_eq!(state.vault_signer_nonce, 1);
+        assert_eq!(
+            state.coin_mint,
+            key("So11111111111111111111111111111111111111112")
+        );
+        assert_eq!(
+            state.coin_vault,
+            key("36c6YqAwyGKQG66XEp2dJc5JqjaBNv7sVghEtJv4c7u6")
+        );
+        assert_eq!(
+            state.pc_vault,
+            key("8CFo8bL8mZQK8abbFyypFMwEDd8tVJjHTTojMLgQTUSZ")
+        );
+        assert_eq!(state.pc_deposits_total, 48_960_211_552);
+        assert_eq!(state.pc_fees_accrued, 1_062_481);
+        assert_eq!(state.pc_dust_threshold, 500);
+        assert_eq!(
+            state.request_queue,
+            key("AZG3tFCFtiCqEwyardENBQNpHqxgzbMw8uKeZEw2nRG5")
+        );
+        assert_eq!(
+            state.event_queue,
+            key("5KKsLVU6TcbVDK4BS6K1DGDxnh4Q9xjYJ8XaDCG5t8ht")
+        );
+        assert_eq!(
+            state.bids,
+            key("14ivtgssEBoBjuZJtSAPKYgpUK7DmnSwuPMqJoVTSgKJ")
+        );
+        assert_eq!(
+            (state.coin_lot_size, state.pc_lot_size, state.fee_rate_bps),
+            (100_000_000, 100, 0)
+        );
+        assert_eq!(state.referrer_rebates_accrued, Some(327_718));
+    }
+
+    #[test]
+    fn rejects_truncated_and_foreign_accounts() {
+        let data = fixture(MARKET_V2);
+        let mut truncated = data[..data.len() - 8].to_vec();
+        truncated.extend_from_slice(b"padding");
+        assert!(matches!(
+            MarketState::unpack(&truncated),
+            Err(StateError::TooShort { .. })
+        ));
+        let mut open_orders = fixture(MARKET_V3);
+        open_orders[5] = (account_flag::INITIALIZED | account_flag::OPEN_ORDERS) as u8;
+        assert!(matches!(
+            MarketState::unpack(&open_orders),
+            Err(StateError::WrongAccountFlags(_))
+        ));
+    }
+}
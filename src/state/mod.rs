@@ -0,0 +1,75 @@
+//! Decoders of the accounts owned by the DEX program: market, open orders, event and
+//! request queues and the bids/asks slabs.
+pub mod event_queue;
+pub mod market;
+pub mod open_orders;
+pub mod request_queue;
+pub mod slab;
+
+use thiserror::Error;
+
+/// Serum wraps the data of every account it owns between these paddings.
+pub const ACCOUNT_HEAD_PADDING: &[u8; 5] = b"serum";
+pub const ACCOUNT_TAIL_PADDING: &[u8; 7] = b"padding";
+
+#[derive(Clone, PartialEq, Debug, Error)]
+pub enum StateError {
+    #[error("account data too short, expected at least {expected} bytes but got {actual}")]
+    TooShort { expected: usize, actual: usize },
+    #[error("unexpected account data length {0}")]
+    WrongLength(usize),
+    #[error("account data is not wrapped in serum paddings")]
+    MissingPadding,
+    #[error("unexpected account flags {0:#x}")]
+    WrongAccountFlags(u64),
+    #[error("invalid slab node tag {0}")]
+    InvalidNodeTag(u32),
+    #[error("slab node {0} breaks the tree")]
+    InvalidNode(u32),
+}
+
+/// Strip the serum paddings and return the inner account data.
+pub fn strip_padding(data: &[u8]) -> Result<&[u8], StateError> {
+    let min_len = ACCOUNT_HEAD_PADDING.len() + ACCOUNT_TAIL_PADDING.len();
+    if data.len() < min_len {
+        return Err(StateError::TooShort {
+            expected: min_len,
+            actual: data.len(),
+        });
+    }
+    let (head, rest) = data.split_at(ACCOUNT_HEAD_PADDING.len());
+    let (inner, tail) = rest.split_at(rest.len() - ACCOUNT_TAIL_PADDING.len());
+    if head != ACCOUNT_HEAD_PADDING || tail != ACCOUNT_TAIL_PADDING {
+        return Err(StateError::MissingPadding);
+    }
+    Ok(inner)
+}
+
+/// Bits of the `account_flags` field at the start of every Serum account.
+pub mod account_flag {
+    pub const INITIALIZED: u64 = 1 << 0;
+    pub const MARKET: u64 = 1 << 1;
+    pub const OPEN_ORDERS: u64 = 1 << 2;
+    pub const REQUEST_QUEUE: u64 = 1 << 3;
+    pub const EVENT_QUEUE: u64 = 1 << 4;
+    pub const BIDS: u64 = 1 << 5;
+    pub const ASKS: u64 = 1 << 6;
+    pub const DISABLED: u64 = 1 << 7;
+    pub const CLOSED: u64 = 1 << 8;
+    pub const PERMISSIONED: u64 = 1 << 9;
+    pub const CRANK_AUTHORITY_REQUIRED: u64 = 1 << 10;
+}
+
+/// Check that `flags` is initialized and has the `expected` kind bit set.
+pub fn check_account_flags(flags: u64, expected: u64) -> Result<(), StateError> {
+    let kinds = account_flag::MARKET
+        | account_flag::OPEN_ORDERS
+        | account_flag::REQUEST_QUEUE
+        | account_flag::EVENT_QUEUE
+        | account_flag::BIDS
+        | account_flag::ASKS;
+    if flags & account_flag::INITIALIZED == 0 || flags & kinds != expected {
+        return Err(StateError::WrongAccountFlags(flags));
+    }
+    Ok(())
+}
@@ -0,0 +1,215 @@
+use super::{account_flag, check_account_flags, strip_padding, StateError};
+use crate::generated::instruction::Side;
+use arrayref::{array_ref, array_refs};
+use solana_program::pubkey::Pubkey;
+
+pub const MAX_ORDERS: usize = 128;
+/// Length of the open orders written by Serum v1, which has no referrer rebates.
+pub const OPEN_ORDERS_V1_LEN: usize = 3208;
+pub const OPEN_ORDERS_LEN: usize = 3216;
+
+/// Funds and resting orders of one owner on one market.
+#[derive(Clone, PartialEq, Debug)]
+pub struct OpenOrders {
+    pub account_flags: u64,
+    pub market: Pubkey,
+    pub owner: Pubkey,
+    pub native_coin_free: u64,
+    pub native_coin_total: u64,
+    pub native_pc_free: u64,
+    pub native_pc_total: u64,
+    /// Bit `i` is set when order slot `i` is free.
+    pub free_slot_bits: u128,
+    /// Bit `i` is set when the order in slot `i` is a bid.
+    pub is_bid_bits: u128,
+    pub orders: Vec<u128>,
+    pub client_order_ids: Vec<u64>,
+    /// `None` for open orders of Serum v1.
+    pub referrer_rebates_accrued: Option<u64>,
+}
+impl OpenOrders {
+    pub fn unpack(data: &[u8]) -> Result<Self, StateError> {
+        let inner = strip_padding(data)?;
+        if inner.len() < OPEN_ORDERS_V1_LEN {
+            return Err(StateError::TooShort {
+                expected: OPEN_ORDERS_V1_LEN,
+                actual: inner.len(),
+            });
+        }
+        if inner.len() != OPEN_ORDERS_V1_LEN && inner.len() != OPEN_ORDERS_LEN {
+            return Err(StateError::WrongLength(inner.len()));
+        }
+        let input = array_ref![inner, 0, OPEN_ORDERS_V1_LEN];
+        let (
+            account_flags,
+            market,
+            owner,
+            native_coin_free,
+            native_coin_total,
+            native_pc_free,
+            native_pc_total,
+            free_slot_bits,
+            is_bid_bits,
+            orders,
+            client_order_ids,
+        ) = array_refs![
+            input,
+            8,
+            32,
+            32,
+            8,
+            8,
+            8,
+            8,
+            16,
+            16,
+            16 * MAX_ORDERS,
+            8 * MAX_ORDERS
+        ];
+        let account_flags = u64::from_le_bytes(*account_flags);
+        check_account_flags(account_flags, account_flag::OPEN_ORDERS)?;
+        let referrer_rebates_accrued = inner
+            .get(OPEN_ORDERS_V1_LEN..OPEN_ORDERS_LEN)
+            .map(|bytes| u64::from_le_bytes(*array_ref![bytes, 0, 8]));
+        Ok(OpenOrders {
+            account_flags,
+            market: Pubkey::new_from_array(*market),
+            owner: Pubkey::new_from_array(*owner),
+            native_coin_free: u64::from_le_bytes(*native_coin_free),
+            native_coin_total: u64::from_le_bytes(*native_coin_total),
+            native_pc_free: u64::from_le_bytes(*native_pc_free),
+            native_pc_total: u64::from_le_bytes(*native_pc_total),
+            free_slot_bits: u128::from_le_bytes(*free_slot_bits),
+            is_bid_bits: u128::from_le_bytes(*is_bid_bits),
+            orders: orders
+                .chunks_exact(16)
+                .map(|chunk| u128::from_le_bytes(*array_ref![chunk, 0, 16]))
+                .collect(),
+            client_order_ids: client_order_ids
+                .chunks_exact(8)
+                .map(|chunk| u64::from_le_bytes(*array_ref![chunk, 0, 8]))
+                .collect(),
+            referrer_rebates_accrued,
+        })
+    }
+    /// Funds reserved by resting orders, `total - free`.
+    pub fn native_coin_locked(&self) -> u64 {
+        self.native_coin_total.saturating_sub(self.native_coin_free)
+    }
+    pub fn native_pc_locked(&self) -> u64 {
+        self.native_pc_total.saturating_sub(self.native_pc_free)
+    }
+    /// Resting orders as `(slot, side, order id, client order id)`.
+    pub fn iter_orders(&self) -> impl Iterator<Item = (u8, Side, u128, u64)> + '_ {
+        (0..MAX_ORDERS)
+            .filter(move |slot| self.free_slot_bits & (1 << slot) == 0)
+            .map(move |slot| {
+                let side = if self.is_bid_bits & (1 << slot) != 0 {
+                    Side::Bid
+                } else {
+                    Side::Ask
+                };
+                (
+                    slot as u8,
+                    side,
+                    self.orders[slot],
+                    self.client_order_ids[slot],
+                )
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::ACCOUNT_HEAD_PADDING;
+    use crate::testing::{key, open_orders_data};
+
+    /// Offset of a field of the inner data in the padded account data.
+    const fn offset(inner_offset: usize) -> usize {
+        ACCOUNT_HEAD_PADDING.len() + inner_offset
+    }
+
+    fn write(data: &mut [u8], inner_offset: usize, bytes: &[u8]) {
+        data[offset(inner_offset)..offset(inner_offset) + bytes.len()].copy_from_slice(bytes);
+    }
+
+    #[test]
+    fn decodes_funds_and_rebates() {
+        let mut data = open_orders_data(key(52), key(53), &[]);
+        assert_eq!(data.len(), 5 + OPEN_ORDERS_LEN + 7);
+        for (index, value) in [10u64, 25, 300, 700].iter().enumerate() {
+            write(&mut data, 72 + 8 * index, &value.to_le_bytes());
+        }
+        write(&mut data, OPEN_ORDERS_V1_LEN, &42u64.to_le_bytes());
+        let open_orders = OpenOrders::unpack(&data).unwrap();
+        assert_eq!((open_orders.market, open_orders.owner), (key(52), key(53)));
+        assert_eq!(
+            (
+                open_orders.native_coin_free,
+                open_orders.native_coin_total,
+                open_orders.native_pc_free,
+                open_orders.native_pc_total
+            ),
+            (10, 25, 300, 700)
+        );
+        assert_eq!(
+            (
+                open_orders.native_coin_locked(),
+                open_orders.native_pc_locked()
+            ),
+            (15, 400)
+        );
+        assert_eq!(open_orders.referrer_rebates_accrued, Some(42));
+    }
+
+    #[test]
+    fn accepts_v1_and_v3_lengths_only() {
+        let data = open_orders_data(key(52), key(53), &[]);
+        let v1 = [&data[..offset(OPEN_ORDERS_V1_LEN)], b"padding"].concat();
+        assert_eq!(
+            OpenOrders::unpack(&v1).unwrap().referrer_rebates_accrued,
+            None
+        );
+        for len in [
+            OPEN_ORDERS_V1_LEN - 8,
+            OPEN_ORDERS_V1_LEN + 4,
+            OPEN_ORDERS_LEN + 8,
+        ] {
+            let mut inner = data[offset(0)..offset(OPEN_ORDERS_V1_LEN)].to_vec();
+            inner.resize(len, 0);
+            let data = [b"serum".as_ref(), &inner, b"padding"].concat();
+            assert!(OpenOrders::unpack(&data).is_err(), "length {}", len);
+        }
+        let mut market = data;
+        market[offset(0)] = (account_flag::INITIALIZED | account_flag::MARKET) as u8;
+        assert!(matches!(
+            OpenOrders::unpack(&market),
+            Err(StateError::WrongAccountFlags(_))
+        ));
+    }
+
+    #[test]
+    fn iterates_used_order_slots() {
+        let mut data = open_orders_data(
+            key(52),
+            key(53),
+            &[(Side::Bid, 5 << 64 | 1, 11), (Side::Ask, 6 << 64 | 2, 12)],
+        );
+        // Free slot 0 and take the last slot with a bid
+        let free_slot_bits: u128 = !(1 << 1 | 1 << 127);
+        let is_bid_bits: u128 = 1 | 1 << 127;
+        write(&mut data, 104, &free_slot_bits.to_le_bytes());
+        write(&mut data, 120, &is_bid_bits.to_le_bytes());
+        write(&mut data, 136 + 16 * 127, &(7u128 << 64 | 3).to_le_bytes());
+        write(&mut data, 136 + 16 * 128 + 8 * 127, &13u64.to_le_bytes());
+        let open_orders = OpenOrders::unpack(&data).unwrap();
+        assert_eq!(
+            open_orders.iter_orders().collect::<Vec<_>>(),
+            [
+                (1, Side::Ask, 6 << 64 | 2, 12),
+                (127, Side::Bid, 7 << 64 | 3, 13)
+            ]
+        );
+    }
+}
@@ -0,0 +1,235 @@
+use super::{account_flag, check_account_flags, strip_padding, StateError};
+use crate::generated::instruction::Side;
+use arrayref::{array_ref, array_refs};
+use solana_program::pubkey::Pubkey;
+
+pub const HEADER_LEN: usize = 32;
+pub const REQUEST_LEN: usize = 80;
+
+pub mod request_flag {
+    pub const NEW_ORDER: u8 = 1 << 0;
+    pub const CANCEL_ORDER: u8 = 1 << 1;
+    pub const BID: u8 = 1 << 2;
+    pub const POST_ONLY: u8 = 1 << 3;
+    pub const IMMEDIATE_OR_CANCEL: u8 = 1 << 4;
+    pub const DECREMENT_TAKE_ON_SELF_TRADE: u8 = 1 << 5;
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct RequestQueueHeader {
+    pub account_flags: u64,
+    pub head: u64,
+    pub count: u64,
+    pub next_seq_num: u64,
+}
+impl RequestQueueHeader {
+    pub fn unpack(input: &[u8; HEADER_LEN]) -> Self {
+        let (account_flags, head, count, next_seq_num) = array_refs![input, 8, 8, 8, 8];
+        RequestQueueHeader {
+            account_flags: u64::from_le_bytes(*account_flags),
+            head: u64::from_le_bytes(*head),
+            count: u64::from_le_bytes(*count),
+            next_seq_num: u64::from_le_bytes(*next_seq_num),
+        }
+    }
+}
+
+/// New order or cancel waiting for MatchOrders.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Request {
+    pub request_flags: u8,
+    pub owner_slot: u8,
+    pub fee_tier: u8,
+    pub self_trade_behavior: u8,
+    /// Maximum coin quantity of a new order, the order id to cancel otherwise.
+    pub max_coin_qty_or_cancel_id: u64,
+    pub native_pc_qty_locked: u64,
+    pub order_id: u128,
+    pub owner: Pubkey,
+    pub client_order_id: u64,
+}
+impl Request {
+    pub fn unpack(input: &[u8; REQUEST_LEN]) -> Self {
+        let (
+            request_flags,
+            owner_slot,
+            fee_tier,
+            self_trade_behavior,
+            _padding,
+            max_coin_qty_or_cancel_id,
+            native_pc_qty_locked,
+            order_id,
+            owner,
+            client_order_id,
+        ) = array_refs![input, 1, 1, 1, 1, 4, 8, 8, 16, 32, 8];
+        Request {
+            request_flags: request_flags[0],
+            owner_slot: owner_slot[0],
+            fee_tier: fee_tier[0],
+            self_trade_behavior: self_trade_behavior[0],
+            max_coin_qty_or_cancel_id: u64::from_le_bytes(*max_coin_qty_or_cancel_id),
+            native_pc_qty_locked: u64::from_le_bytes(*native_pc_qty_locked),
+            order_id: u128::from_le_bytes(*order_id),
+            owner: Pubkey::new_from_array(*owner),
+            client_order_id: u64::from_le_bytes(*client_order_id),
+        }
+    }
+    pub fn is_new_order(&self) -> bool {
+        self.request_flags & request_flag::NEW_ORDER != 0
+    }
+    pub fn is_cancel_order(&self) -> bool {
+        self.request_flags & request_flag::CANCEL_ORDER != 0
+    }
+    pub fn is_post_only(&self) -> bool {
+        self.request_flags & request_flag::POST_ONLY != 0
+    }
+    pub fn is_immediate_or_cancel(&self) -> bool {
+        self.request_flags & request_flag::IMMEDIATE_OR_CANCEL != 0
+    }
+    pub fn side(&self) -> Side {
+        if self.request_flags & request_flag::BID != 0 {
+            Side::Bid
+        } else {
+            Side::Ask
+        }
+    }
+}
+
+/// Ring buffer of pending requests, `requests` keeps the raw buffer order.
+#[derive(Clone, PartialEq, Debug)]
+pub struct RequestQueue {
+    pub header: RequestQueueHeader,
+    pub requests: Vec<Request>,
+}
+impl RequestQueue {
+    pub fn unpack(data: &[u8]) -> Result<Self, StateError> {
+        let inner = strip_padding(data)?;
+        if inner.len() < HEADER_LEN {
+            return Err(StateError::TooShort {
+                expected: HEADER_LEN,
+                actual: inner.len(),
+            });
+        }
+        if !(inner.len() - HEADER_LEN).is_multiple_of(REQUEST_LEN) {
+            return Err(StateError::WrongLength(inner.len()));
+        }
+        let (header, buffer) = inner.split_at(HEADER_LEN);
+        let header = RequestQueueHeader::unpack(array_ref![header, 0, HEADER_LEN]);
+        check_account_flags(header.account_flags, account_flag::REQUEST_QUEUE)?;
+        let requests = buffer
+            .chunks_exact(REQUEST_LEN)
+            .map(|chunk| Request::unpack(array_ref![chunk, 0, REQUEST_LEN]))
+            .collect();
+        Ok(RequestQueue { header, requests })
+    }
+    pub fn capacity(&self) -> u64 {
+        self.requests.len() as u64
+    }
+    /// Requests currently in the queue, oldest first.
+    pub fn iter(&self) -> impl Iterator<Item = &Request> + '_ {
+        let capacity = self.capacity().max(1);
+        (0..self.header.count.min(self.capacity())).map(move |offset| {
+            let index = (self.header.head + offset) % capacity;
+            &self.requests[index as usize]
+        })
+    }
+    /// Requests popped since `previous` was captured, oldest first. Popped requests stay in
+    /// the buffer until new requests overwrite them.
+    pub fn popped_since(&self, previous: &RequestQueueHeader) -> Vec<&Request> {
+        let capacity = self.capacity();
+        if capacity == 0 {
+            return Vec::new();
+        }
+        let popped = (self.header.head + capacity - previous.head % capacity) % capacity;
+        (0..popped)
+            .map(|offset| &self.requests[((previous.head + offset) % capacity) as usize])
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{key, request_queue_data};
+
+    fn request(request_flags: u8, order_id: u128) -> Request {
+        Request {
+            request_flags,
+            owner_slot: 0,
+            fee_tier: 0,
+            self_trade_behavior: 0,
+            max_coin_qty_or_cancel_id: 0,
+            native_pc_qty_locked: 0,
+            order_id,
+            owner: key(51),
+            client_order_id: 0,
+        }
+    }
+
+    #[test]
+    fn iterates_across_the_end_of_the_buffer() {
+        let requests = (0..4)
+            .map(|index| request(request_flag::NEW_ORDER, index))
+            .collect::<Vec<_>>();
+        let queue = RequestQueue::unpack(&request_queue_data(2, 3, &requests)).unwrap();
+        assert_eq!(queue.capacity(), 4);
+        assert_eq!(queue.requests, requests);
+        assert_eq!(
+            queue
+                .iter()
+                .map(|request| request.order_id)
+                .collect::<Vec<_>>(),
+            [2, 3, 0]
+        );
+        let queue = RequestQueue::unpack(&request_queue_data(3, 0, &requests)).unwrap();
+        assert_eq!(queue.iter().count(), 0);
+        // Two requests were popped across the end of the buffer
+        let previous = RequestQueue::unpack(&request_queue_data(3, 2, &requests)).unwrap();
+        let queue = RequestQueue::unpack(&request_queue_data(1, 1, &requests)).unwrap();
+        assert_eq!(
+            queue
+                .popped_since(&previous.header)
+                .iter()
+                .map(|request| request.order_id)
+                .collect::<Vec<_>>(),
+            [3, 0]
+        );
+        assert!(queue.popped_since(&queue.header).is_empty());
+    }
+
+    #[test]
+    fn reads_request_flags() {
+        let post_only_bid = request(
+            request_flag::NEW_ORDER | request_flag::BID | request_flag::POST_ONLY,
+            1,
+        );
+        assert!(post_only_bid.is_new_order() && post_only_bid.is_post_only());
+        assert!(!post_only_bid.is_cancel_order() && !post_only_bid.is_immediate_or_cancel());
+        assert_eq!(post_only_bid.side(), Side::Bid);
+        let ioc_ask = request(
+            request_flag::NEW_ORDER | request_flag::IMMEDIATE_OR_CANCEL,
+            1,
+        );
+        assert!(ioc_ask.is_immediate_or_cancel() && !ioc_ask.is_post_only());
+        assert_eq!(ioc_ask.side(), Side::Ask);
+        let cancel = request(request_flag::CANCEL_ORDER, 1);
+        assert!(cancel.is_cancel_order() && !cancel.is_new_order());
+    }
+
+    #[test]
+    fn rejects_foreign_and_truncated_accounts() {
+        let data = request_queue_data(0, 1, &[request(request_flag::NEW_ORDER, 1)]);
+        let mut truncated = data[..data.len() - 8].to_vec();
+        truncated.extend_from_slice(b"padding");
+        assert_eq!(
+            RequestQueue::unpack(&truncated),
+            Err(StateError::WrongLength(HEADER_LEN + REQUEST_LEN - 1))
+        );
+        let mut event_queue = data;
+        event_queue[5] = (account_flag::INITIALIZED | account_flag::EVENT_QUEUE) as u8;
+        assert!(matches!(
+            RequestQueue::unpack(&event_queue),
+            Err(StateError::WrongAccountFlags(_))
+        ));
+    }
+}
@@ -0,0 +1,330 @@
+use super::{account_flag, strip_padding, StateError};
+use crate::generated::instruction::Side;
+use crate::order_id;
+use arrayref::{array_ref, array_refs};
+use solana_program::pubkey::Pubkey;
+
+pub const HEADER_LEN: usize = 40;
+pub const NODE_LEN: usize = 72;
+
+pub mod node_tag {
+    pub const UNINITIALIZED: u32 = 0;
+    pub const INNER: u32 = 1;
+    pub const LEAF: u32 = 2;
+    pub const FREE: u32 = 3;
+    pub const LAST_FREE: u32 = 4;
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct SlabHeader {
+    pub account_flags: u64,
+    pub bump_index: u64,
+    pub free_list_len: u64,
+    pub free_list_head: u32,
+    pub root_node: u32,
+    pub leaf_count: u64,
+}
+impl SlabHeader {
+    pub fn unpack(input: &[u8; HEADER_LEN]) -> Self {
+        let (account_flags, bump_index, free_list_len, free_list_head, root_node, leaf_count) =
+            array_refs![input, 8, 8, 8, 4, 4, 8];
+        SlabHeader {
+            account_flags: u64::from_le_bytes(*account_flags),
+            bump_index: u64::from_le_bytes(*bump_index),
+            free_list_len: u64::from_le_bytes(*free_list_len),
+            free_list_head: u32::from_le_bytes(*free_list_head),
+            root_node: u32::from_le_bytes(*root_node),
+            leaf_count: u64::from_le_bytes(*leaf_count),
+        }
+    }
+}
+
+/// Resting order, `key` is its order id.
+#[derive(Clone, PartialEq, Debug)]
+pub struct LeafNode {
+    pub owner_slot: u8,
+    pub fee_tier: u8,
+    pub key: u128,
+    pub owner: Pubkey,
+    pub quantity: u64,
+    pub client_order_id: u64,
+}
+impl LeafNode {
+    pub fn price_lots(&self) -> u64 {
+        order_id::price_lots(self.key)
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum SlabNode {
+    Uninitialized,
+    /// Keys below the node share their first `prefix_len` bits with `key`, `children[0]`
+    /// holds the keys whose next bit is 0.
+    Inner {
+        prefix_len: u32,
+        key: u128,
+        children: [u32; 2],
+    },
+    Leaf(LeafNode),
+    Free {
+        next: u32,
+    },
+    LastFree,
+}
+impl SlabNode {
+    pub fn unpack(input: &[u8; NODE_LEN]) -> Result<Self, StateError> {
+        let (tag, body) = array_refs![input, 4, 68];
+        match u32::from_le_bytes(*tag) {
+            node_tag::UNINITIALIZED => Ok(SlabNode::Uninitialized),
+            node_tag::INNER => {
+                let (prefix_len, key, left, right, _padding) = array_refs![body, 4, 16, 4, 4, 40];
+                Ok(SlabNode::Inner {
+                    prefix_len: u32::from_le_bytes(*prefix_len),
+                    key: u128::from_le_bytes(*key),
+                    children: [u32::from_le_bytes(*left), u32::from_le_bytes(*right)],
+                })
+            }
+            node_tag::LEAF => {
+                let (owner_slot, fee_tier, _padding, key, owner, quantity, client_order_id) =
+                    array_refs![body, 1, 1, 2, 16, 32, 8, 8];
+                Ok(SlabNode::Leaf(LeafNode {
+                    owner_slot: owner_slot[0],
+                    fee_tier: fee_tier[0],
+                    key: u128::from_le_bytes(*key),
+                    owner: Pubkey::new_from_array(*owner),
+                    quantity: u64::from_le_bytes(*quantity),
+                    client_order_id: u64::from_le_bytes(*client_order_id),
+                }))
+            }
+            node_tag::FREE => Ok(SlabNode::Free {
+                next: u32::from_le_bytes(*array_ref![body, 0, 4]),
+            }),
+            node_tag::LAST_FREE => Ok(SlabNode::LastFree),
+            tag => Err(StateError::InvalidNodeTag(tag)),
+        }
+    }
+}
+
+/// Critbit tree holding one side of the order book.
+#[derive(Clone, PartialEq, Debug)]
+pub struct Slab {
+    pub header: SlabHeader,
+    pub nodes: Vec<SlabNode>,
+}
+impl Slab {
+    pub fn unpack(data: &[u8]) -> Result<Self, StateError> {
+        let inner = strip_padding(data)?;
+        if inner.len() < HEADER_LEN {
+            return Err(StateError::TooShort {
+                expected: HEADER_LEN,
+                actual: inner.len(),
+            });
+        }
+        let (header, buffer) = inner.split_at(HEADER_LEN);
+        let header = SlabHeader::unpack(array_ref![header, 0, HEADER_LEN]);
+        let kinds = account_flag::BIDS | account_flag::ASKS;
+        let kind = header.account_flags & kinds;
+        if header.account_flags & account_flag::INITIALIZED == 0 || kind == 0 || kind == kinds {
+            return Err(StateError::WrongAccountFlags(header.account_flags));
+        }
+        // Nodes past the bump index were never used
+        let nodes = buffer
+            .chunks_exact(NODE_LEN)
+            .take(header.bump_index as usize)
+            .map(|chunk| SlabNode::unpack(array_ref![chunk, 0, NODE_LEN]))
+            .collect::<Result<_, _>>()?;
+        Ok(Slab { header, nodes })
+    }
+    pub fn side(&self) -> Side {
+        if self.header.account_flags & account_flag::BIDS != 0 {
+            Side::Bid
+        } else {
+            Side::Ask
+        }
+    }
+    /// Resting orders by ascending key.
+    pub fn leaves(&self) -> Result<Vec<&LeafNode>, StateError> {
+        let mut leaves = Vec::new();
+        if self.header.leaf_count == 0 {
+            return Ok(leaves);
+        }
+        let mut stack = vec![self.header.root_node];
+        while let Some(index) = stack.pop() {
+            // A well formed tree has leaf_count - 1 inner nodes
+            if leaves.len() as u64 + stack.len() as u64 > self.header.leaf_count {
+                return Err(StateError::InvalidNode(index));
+            }
+            match self.nodes.get(index as usize) {
+                Some(SlabNode::Inner { children, .. }) => {
+                    stack.push(children[1]);
+                    stack.push(children[0]);
+                }
+                Some(SlabNode::Leaf(leaf)) => leaves.push(leaf),
+                _ => return Err(StateError::InvalidNode(index)),
+            }
+        }
+        Ok(leaves)
+    }
+    /// Resting orders from the best price, the earliest order first within a price.
+    pub fn orders(&self) -> Result<Vec<&LeafNode>, StateError> {
+        let mut leaves = self.leaves()?;
+        // Bid keys hold the inverted sequence number, so the highest key is the best bid
+        if self.side() == Side::Bid {
+            leaves.reverse();
+        }
+        Ok(leaves)
+    }
+    /// Indexes of the free nodes, from the head of the free list.
+    pub fn free_list(&self) -> Result<Vec<u32>, StateError> {
+        let mut free = Vec::new();
+        let mut index = self.header.free_list_head;
+        for _ in 0..self.header.free_list_len {
+            free.push(index);
+            match self.nodes.get(index as usize) {
+                Some(SlabNode::Free { next }) => index = *next,
+                Some(SlabNode::LastFree) => break,
+                _ => return Err(StateError::InvalidNode(index)),
+            }
+        }
+        Ok(free)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{key, slab_data};
+
+    fn leaf(price_lots: u64, seq_num: u64, quantity: u64) -> SlabNode {
+        SlabNode::Leaf(LeafNode {
+            owner_slot: 0,
+            fee_tier: 0,
+            key: (price_lots as u128) << 64 | seq_num as u128,
+            owner: key(40),
+            quantity,
+            client_order_id: seq_num,
+        })
+    }
+
+    fn inner(children: [u32; 2]) -> SlabNode {
+        SlabNode::Inner {
+            prefix_len: 0,
+            key: 0,
+            children,
+        }
+    }
+
+    /// Three leaves under an inner root at node 3, with free and uninitialized nodes in
+    /// between that the tree does not reach.
+    fn slab(account_flags: u64) -> Vec<u8> {
+        let nodes = [
+            leaf(12, 3, 30),
+            SlabNode::Free { next: 6 },
+            leaf(10, 1, 10),
+            inner([2, 5]),
+            SlabNode::Uninitialized,
+            inner([7, 0]),
+            SlabNode::LastFree,
+            leaf(11, 2, 20),
+        ];
+        let header = SlabHeader {
+            account_flags: account_flag::INITIALIZED | account_flags,
+            bump_index: nodes.len() as u64,
+            free_list_len: 2,
+            free_list_head: 1,
+            root_node: 3,
+            leaf_count: 3,
+        };
+        slab_data(&header, &nodes)
+    }
+
+    fn keys(leaves: &[&LeafNode]) -> Vec<(u64, u64)> {
+        leaves
+            .iter()
+            .map(|leaf| (leaf.price_lots(), leaf.quantity))
+            .collect()
+    }
+
+    #[test]
+    fn walks_leaves_by_ascending_key() {
+        let slab = Slab::unpack(&slab(account_flag::ASKS)).unwrap();
+        assert_eq!(slab.side(), Side::Ask);
+        assert_eq!(slab.nodes.len(), 8);
+        assert_eq!(
+            keys(&slab.leaves().unwrap()),
+            [(10, 10), (11, 20), (12, 30)]
+        );
+        assert_eq!(
+            keys(&slab.orders().unwrap()),
+            [(10, 10), (11, 20), (12, 30)]
+        );
+        assert_eq!(slab.free_list().unwrap(), [1, 6]);
+    }
+
+    #[test]
+    fn orders_bids_from_the_highest_key() {
+        let slab = Slab::unpack(&slab(account_flag::BIDS)).unwrap();
+        assert_eq!(slab.side(), Side::Bid);
+        assert_eq!(
+            keys(&slab.orders().unwrap()),
+            [(12, 30), (11, 20), (10, 10)]
+        );
+        // At one price the older bid has the higher key, its sequence number is inverted
+        let nodes = [inner([1, 2]), leaf(10, !8, 5), leaf(10, !7, 6)];
+        let header = SlabHeader {
+            account_flags: account_flag::INITIALIZED | account_flag::BIDS,
+            bump_index: 3,
+            free_list_len: 0,
+            free_list_head: 0,
+            root_node: 0,
+            leaf_count: 2,
+        };
+        let slab = Slab::unpack(&slab_data(&header, &nodes)).unwrap();
+        let orders = slab.orders().unwrap();
+        assert_eq!(
+            orders
+                .iter()
+                .map(|leaf| order_id::seq_num(leaf.key, &Side::Bid))
+                .collect::<Vec<_>>(),
+            [7, 8]
+        );
+    }
+
+    #[test]
+    fn rejects_broken_trees() {
+        let header = SlabHeader {
+            account_flags: account_flag::INITIALIZED | account_flag::ASKS,
+            bump_index: 2,
+            free_list_len: 0,
+            free_list_head: 0,
+            root_node: 0,
+            leaf_count: 2,
+        };
+        // A child past the bump index
+        let slab = Slab::unpack(&slab_data(&header, &[inner([1, 9]), leaf(10, 1, 1)])).unwrap();
+        assert_eq!(slab.leaves(), Err(StateError::InvalidNode(9)));
+        // A child on the free list
+        let nodes = [inner([1, 2]), leaf(10, 1, 1), SlabNode::LastFree];
+        let slab = Slab::unpack(&slab_data(
+            &SlabHeader {
+                bump_index: 3,
+                ..header.clone()
+            },
+            &nodes,
+        ))
+        .unwrap();
+        assert_eq!(slab.leaves(), Err(StateError::InvalidNode(2)));
+        // An inner node that is its own child
+        let slab = Slab::unpack(&slab_data(&header, &[inner([0, 1]), leaf(10, 1, 1)])).unwrap();
+        assert!(matches!(slab.leaves(), Err(StateError::InvalidNode(_))));
+        // Bids and asks flags at once
+        let both = SlabHeader {
+            account_flags: header.account_flags | account_flag::BIDS,
+            ..header
+        };
+        assert!(matches!(
+            Slab::unpack(&slab_data(&both, &[])),
+            Err(StateError::WrongAccountFlags(_))
+        ));
+    }
+}
@@ -0,0 +1,255 @@
+//! Helpers shared by the tests.
+use crate::batch;
+use crate::context::InstructionContext;
+use crate::fork;
+use crate::generated::instruction::Side;
+use crate::program::DexVersion;
+use crate::state::account_flag;
+use crate::state::event_queue::Event;
+use crate::state::open_orders::MAX_ORDERS;
+use crate::state::request_queue::Request;
+use crate::state::slab::{node_tag, SlabHeader, SlabNode, NODE_LEN};
+use crate::STORE;
+use lazy_static::lazy_static;
+use massbit_solana_sdk::entity::{Entity, Value};
+use massbit_solana_sdk::store::IndexStore;
+use massbit_solana_sdk::types::SolanaBlock;
+use solana_program::pubkey::Pubkey;
+use solana_transaction_status::ConfirmedBlock;
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::{Mutex, MutexGuard};
+
+lazy_static! {
+    /// Held by the tests that go through the global state: derived state, batch and store.
+    static ref STATE: Mutex<()> = Mutex::new(());
+    /// Entities the test store received.
+    static ref STORED: Mutex<Vec<(String, Entity)>> = Mutex::new(Vec::new());
+    /// Slot whose flush the test store fails.
+    static ref FAILING_FLUSH: Mutex<Option<u64>> = Mutex::new(None);
+}
+
+struct TestStore;
+
+impl IndexStore for TestStore {
+    fn save(&mut self, entity_name: String, data: Entity) {
+        STORED.lock().unwrap().push((entity_name, data));
+    }
+    fn flush(&mut self, _block_hash: &String, block_slot: u64) -> Result<(), Box<dyn Error>> {
+        match *FAILING_FLUSH.lock().unwrap() {
+            Some(slot) if slot == block_slot => Err("Store is down".into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Take the global state for a test, with nothing pending, no indexed chain and an empty
+/// store. Tests use keys of their own, so derived state left by other tests does not get in
+/// the way.
+pub fn lock_state() -> MutexGuard<'static, ()> {
+    let guard = STATE.lock().unwrap_or_else(|err| err.into_inner());
+    unsafe {
+        if (*std::ptr::addr_of!(STORE)).is_none() {
+            STORE = Some(Box::leak(Box::new(TestStore)));
+        }
+    }
+    batch::discard();
+    // Blocks indexed by other tests are not the parents of the blocks of this one
+    fork::truncate(0);
+    STORED.lock().unwrap().clear();
+    *FAILING_FLUSH.lock().unwrap() = None;
+    guard
+}
+
+/// Make the test store fail the flush of `slot`.
+pub fn fail_flush(slot: u64) {
+    *FAILING_FLUSH.lock().unwrap() = Some(slot);
+}
+
+/// Latest stored row of each `entity_name`, by id.
+pub fn stored(entity_name: &str) -> HashMap<String, Entity> {
+    STORED
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(name, _)| name == entity_name)
+        .filter_map(|(_, entity)| match entity.get("id") {
+            Some(Value::String(id)) => Some((id.clone(), entity.clone())),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Market accounts as stored on chain, padding included: a disabled v2 market and the v3
+/// SOL/USDC market.
+pub const MARKET_V2: &str = include_str!("state/fixtures/market_v2.hex");
+pub const MARKET_V3: &str = include_str!("state/fixtures/market_v3.hex");
+
+pub fn fixture(hex: &str) -> Vec<u8> {
+    hex::decode(hex.trim()).unwrap()
+}
+
+pub fn key(seed: u8) -> Pubkey {
+    Pubkey::new_from_array([seed; 32])
+}
+
+/// Empty block of `slot` whose parent is the previous slot, hashes as in `ctx`.
+pub fn block(slot: u64) -> SolanaBlock {
+    SolanaBlock {
+        version: "1".to_string(),
+        timestamp: 0,
+        block_number: slot,
+        block: ConfirmedBlock {
+            previous_blockhash: format!("hash-{}", slot - 1),
+            blockhash: format!("hash-{}", slot),
+            parent_slot: slot - 1,
+            ..ConfirmedBlock::default()
+        },
+    }
+}
+
+/// Top level instruction `instruction_index` of the only transaction of `slot`.
+pub fn ctx(slot: u64, instruction_index: usize) -> InstructionContext {
+    InstructionContext {
+        program_id: key(1),
+        dex_version: DexVersion::V3,
+        slot,
+        block_hash: format!("hash-{}", slot),
+        block_time: None,
+        signature: format!("signature-{}", slot),
+        fee_payer: key(2).to_string(),
+        error: None,
+        tx_index: 0,
+        instruction_index,
+        inner_index: None,
+        invoking_program: None,
+        instruction_version: Some(0),
+    }
+}
+
+/// Event queue account holding `events`, the last of them at `seq_num - 1`.
+pub fn event_queue_data(seq_num: u64, events: &[Event]) -> Vec<u8> {
+    let mut data = b"serum".to_vec();
+    let account_flags = account_flag::INITIALIZED | account_flag::EVENT_QUEUE;
+    for value in [account_flags, 0, events.len() as u64, seq_num] {
+        data.extend_from_slice(&value.to_le_bytes());
+    }
+    for event in events {
+        data.extend_from_slice(&[event.event_flags, event.owner_slot, event.fee_tier]);
+        data.extend_from_slice(&[0; 5]);
+        data.extend_from_slice(&event.native_qty_released.to_le_bytes());
+        data.extend_from_slice(&event.native_qty_paid.to_le_bytes());
+        data.extend_from_slice(&event.native_fee_or_rebate.to_le_bytes());
+        data.extend_from_slice(&event.order_id.to_le_bytes());
+        data.extend_from_slice(event.owner.as_ref());
+        data.extend_from_slice(&event.client_order_id.to_le_bytes());
+    }
+    data.extend_from_slice(b"padding");
+    data
+}
+
+/// Request queue account holding `requests`, `count` of them queued from `head` on.
+pub fn request_queue_data(head: u64, count: u64, requests: &[Request]) -> Vec<u8> {
+    let mut data = b"serum".to_vec();
+    let account_flags = account_flag::INITIALIZED | account_flag::REQUEST_QUEUE;
+    for value in [account_flags, head, count, 0] {
+        data.extend_from_slice(&value.to_le_bytes());
+    }
+    for request in requests {
+        data.extend_from_slice(&[
+            request.request_flags,
+            request.owner_slot,
+            request.fee_tier,
+            request.self_trade_behavior,
+        ]);
+        data.extend_from_slice(&[0; 4]);
+        data.extend_from_slice(&request.max_coin_qty_or_cancel_id.to_le_bytes());
+        data.extend_from_slice(&request.native_pc_qty_locked.to_le_bytes());
+        data.extend_from_slice(&request.order_id.to_le_bytes());
+        data.extend_from_slice(request.owner.as_ref());
+        data.extend_from_slice(&request.client_order_id.to_le_bytes());
+    }
+    data.extend_from_slice(b"padding");
+    data
+}
+
+/// Open orders account of `owner` on `market` with no funds and `orders` resting, given as
+/// side, order id and client order id.
+pub fn open_orders_data(market: Pubkey, owner: Pubkey, orders: &[(Side, u128, u64)]) -> Vec<u8> {
+    let mut data = b"serum".to_vec();
+    let account_flags = account_flag::INITIALIZED | account_flag::OPEN_ORDERS;
+    data.extend_from_slice(&account_flags.to_le_bytes());
+    data.extend_from_slice(market.as_ref());
+    data.extend_from_slice(owner.as_ref());
+    data.extend_from_slice(&[0; 32]);
+    let mut free_slot_bits = !0u128;
+    let mut is_bid_bits = 0u128;
+    let mut order_ids = vec![0u128; MAX_ORDERS];
+    let mut client_order_ids = vec![0u64; MAX_ORDERS];
+    for (slot, (side, order_id, client_order_id)) in orders.iter().enumerate() {
+        free_slot_bits &= !(1 << slot);
+        if *side == Side::Bid {
+            is_bid_bits |= 1 << slot;
+        }
+        order_ids[slot] = *order_id;
+        client_order_ids[slot] = *client_order_id;
+    }
+    data.extend_from_slice(&free_slot_bits.to_le_bytes());
+    data.extend_from_slice(&is_bid_bits.to_le_bytes());
+    for order_id in order_ids {
+        data.extend_from_slice(&order_id.to_le_bytes());
+    }
+    for client_order_id in client_order_ids {
+        data.extend_from_slice(&client_order_id.to_le_bytes());
+    }
+    data.extend_from_slice(&0u64.to_le_bytes());
+    data.extend_from_slice(b"padding");
+    data
+}
+
+/// Bids or asks account with `header` and `nodes`, the nodes up to the bump index.
+pub fn slab_data(header: &SlabHeader, nodes: &[SlabNode]) -> Vec<u8> {
+    let mut data = b"serum".to_vec();
+    data.extend_from_slice(&header.account_flags.to_le_bytes());
+    data.extend_from_slice(&header.bump_index.to_le_bytes());
+    data.extend_from_slice(&header.free_list_len.to_le_bytes());
+    data.extend_from_slice(&header.free_list_head.to_le_bytes());
+    data.extend_from_slice(&header.root_node.to_le_bytes());
+    data.extend_from_slice(&header.leaf_count.to_le_bytes());
+    for node in nodes {
+        let mut node_data = Vec::with_capacity(NODE_LEN);
+        match node {
+            SlabNode::Uninitialized => {
+                node_data.extend_from_slice(&node_tag::UNINITIALIZED.to_le_bytes())
+            }
+            SlabNode::Inner {
+                prefix_len,
+                key,
+                children,
+            } => {
+                node_data.extend_from_slice(&node_tag::INNER.to_le_bytes());
+                node_data.extend_from_slice(&prefix_len.to_le_bytes());
+                node_data.extend_from_slice(&key.to_le_bytes());
+                node_data.extend_from_slice(&children[0].to_le_bytes());
+                node_data.extend_from_slice(&children[1].to_le_bytes());
+            }
+            SlabNode::Leaf(leaf) => {
+                node_data.extend_from_slice(&node_tag::LEAF.to_le_bytes());
+                node_data.extend_from_slice(&[leaf.owner_slot, leaf.fee_tier, 0, 0]);
+                node_data.extend_from_slice(&leaf.key.to_le_bytes());
+                node_data.extend_from_slice(leaf.owner.as_ref());
+                node_data.extend_from_slice(&leaf.quantity.to_le_bytes());
+                node_data.extend_from_slice(&leaf.client_order_id.to_le_bytes());
+            }
+            SlabNode::Free { next } => {
+                node_data.extend_from_slice(&node_tag::FREE.to_le_bytes());
+                node_data.extend_from_slice(&next.to_le_bytes());
+            }
+            SlabNode::LastFree => node_data.extend_from_slice(&node_tag::LAST_FREE.to_le_bytes()),
+        }
+        node_data.resize(NODE_LEN, 0);
+        data.extend_from_slice(&node_data);
+    }
+    data.extend_from_slice(b"padding");
+    data
+}
@@ -0,0 +1,45 @@
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use solana_program::pubkey::Pubkey;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+/// Mints bundled with the indexer, so that lot sizes can be turned into UI amounts without
+/// fetching mint accounts.
+const TOKEN_LIST: &str = include_str!("../tokens.json");
+
+lazy_static! {
+    pub static ref TOKENS: HashMap<Pubkey, TokenInfo> = load_tokens(TOKEN_LIST);
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TokenInfo {
+    pub mint: String,
+    pub symbol: String,
+    pub decimals: u8,
+}
+
+pub fn load_tokens(list: &str) -> HashMap<Pubkey, TokenInfo> {
+    let tokens: Vec<TokenInfo> = serde_json::from_str(list).unwrap_or_else(|err| {
+        log::warn!("Cannot parse token list: {:?}", err);
+        Vec::new()
+    });
+    tokens
+        .into_iter()
+        .filter_map(|token| match Pubkey::from_str(&token.mint) {
+            Ok(mint) => Some((mint, token)),
+            Err(_) => {
+                log::warn!("Ignore token with invalid mint {}", token.mint);
+                None
+            }
+        })
+        .collect()
+}
+
+pub fn decimals(mint: &Pubkey) -> Option<u8> {
+    TOKENS.get(mint).map(|token| token.decimals)
+}
+
+pub fn symbol(mint: &Pubkey) -> Option<String> {
+    TOKENS.get(mint).map(|token| token.symbol.clone())
+}